@@ -0,0 +1,182 @@
+//! Symbolization from a separate debug file, for stripped binaries.
+
+use crate::{truncated, truncated_path, Call, LeakTracer, ReentrancyGuard};
+use addr2line::gimli::{self, EndianSlice, RunTimeEndian};
+use heapless::Vec as HeaplessVec;
+use object::{Object, ObjectSection, ObjectSegment};
+use std::io;
+use std::path::Path;
+
+/// Load address of the module containing `addr`.
+fn module_base(addr: usize) -> Option<usize> {
+    crate::module::lookup(addr).map(|module| module.base)
+}
+
+fn invalid_data(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+type Context<'data> = addr2line::Context<EndianSlice<'data, RunTimeEndian>>;
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Symbolizes the records not resolved yet using the DWARF in `path`, e.g.
+    /// the `.debug` file split off a stripped executable, instead of what the
+    /// process can find itself.
+    ///
+    /// The file has to describe the executable this tracer is linked into;
+    /// frames in other modules (shared libraries) are resolved in process.
+    /// Only [`ResolveMode::Lazy`](crate::ResolveMode::Lazy) records keep their
+    /// addresses until then. Compressed debug sections are not supported.
+    pub fn resolve_with_debug_file(&self, path: &Path) -> io::Result<()> {
+        let _guard = ReentrancyGuard::enter();
+
+        let data = std::fs::read(path)?;
+        let file = object::File::parse(&*data).map_err(invalid_data)?;
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|section| section.data().ok())
+                .unwrap_or(&[]);
+            Ok(EndianSlice::new(data, endian))
+        })
+        .map_err(invalid_data)?;
+        let context = Context::from_dwarf(dwarf).map_err(invalid_data)?;
+
+        // Addresses in the file are relative to where its first segment would
+        // be loaded, the frames to where it actually was.
+        let exe_base = module_base(Self::resolve_with_debug_file as *const () as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "executable not found"))?;
+        let first_segment = file.segments().map(|segment| segment.address()).min();
+        let bias = first_segment.unwrap_or(0) as usize;
+
+        let pending: Vec<(usize, u64)> = self
+            .inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| !record.symbolized)
+            .map(|record| (record.ptr, record.seq))
+            .collect();
+        for (ptr, seq) in pending {
+            let frames = match self.inner.allocates.lock().get(&ptr) {
+                Some(record) if record.seq == seq && !record.symbolized => record.frames.clone(),
+                _ => continue,
+            };
+            let mut stack = HeaplessVec::new();
+            for &ip in frames.iter() {
+                if module_base(ip) == Some(exe_base) {
+                    // The return address points after the call.
+                    let probe = (ip - exe_base + bias).saturating_sub(1);
+                    push_frames(&context, probe as u64, ip, &mut stack);
+                } else {
+                    for call in crate::resolve_frames::<STACK_SIZE, NAME_LEN>(
+                        &[ip],
+                        Some(&self.inner.symbols.read()),
+                    ) {
+                        let _ = stack.push(call);
+                    }
+                }
+                if stack.is_full() {
+                    break;
+                }
+            }
+            self.drop_locations(&mut stack);
+            if let Some(record) = self.inner.allocates.lock().get_mut(&ptr) {
+                if record.seq == seq {
+                    record.stack = stack;
+                    record.symbolized = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pushes the (inlined) functions at `probe` innermost first, keeping what fits.
+fn push_frames<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    context: &Context<'_>,
+    probe: u64,
+    ip: usize,
+    stack: &mut HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+) {
+    let Ok(mut frames) = context.find_frames(probe).skip_all_loads() else {
+        return;
+    };
+    while let Ok(Some(frame)) = frames.next() {
+        let raw_name = frame.function.as_ref().and_then(|f| f.raw_name().ok());
+        let name = raw_name.as_ref().and_then(|raw| {
+            backtrace::SymbolName::new(raw.as_bytes())
+                .as_str()
+                .map(truncated)
+        });
+        let location = frame.location.as_ref();
+        let _ = stack.push(Call {
+            name,
+            filename: location
+                .and_then(|l| l.file)
+                .map(|file| truncated_path(file.chars())),
+            line: location.and_then(|l| l.line),
+            col: location.and_then(|l| l.column),
+            addr: 0,
+            ip,
+            module: None,
+            offset: 0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::frame_fn;
+    use crate::{LeakTracer, ResolveMode};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    frame_fn! {
+        fn alloc_for_debug_file(tracer: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+            unsafe { tracer.alloc(layout) }
+        }
+    }
+
+    // The release profile builds without debug info, and DWARF is all
+    // `resolve_with_debug_file` reads: from an optimized test binary there
+    // is nothing to resolve, whatever is passed to it. Stripped binaries
+    // are what the feature is for, but the test has no other file at hand.
+    #[test]
+    #[cfg_attr(
+        not(debug_assertions),
+        ignore = "release test binaries have no debug info to resolve from"
+    )]
+    fn resolves_from_the_executable_as_debug_file() {
+        let tracer = LeakTracer::<16>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let ptr = alloc_for_debug_file(&tracer, layout);
+
+        // The test binary isn't stripped, so it is its own debug file.
+        let exe = std::env::current_exe().unwrap();
+        tracer.resolve_with_debug_file(&exe).unwrap();
+
+        let leaks = tracer.get_leaks();
+        let record = &leaks[&(ptr as usize)];
+        assert!(record.symbolized);
+        let call = record
+            .stack
+            .iter()
+            .find(|call| {
+                call.name
+                    .as_ref()
+                    .is_some_and(|name| name.contains("alloc_for_debug_file"))
+            })
+            .expect("the allocating function is found");
+        assert!(call.filename.as_ref().unwrap().ends_with("debug_file.rs"));
+        assert!(call.line.is_some());
+
+        unsafe { tracer.dealloc(ptr, layout) };
+    }
+}
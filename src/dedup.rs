@@ -0,0 +1,274 @@
+//! Merging identical live allocations into one record, see
+//! [`LeakTracerBuilder::deduplicate`](crate::LeakTracerBuilder::deduplicate).
+
+use crate::{AllocationRecord, LeakTracer, Records};
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashMap;
+use std::alloc::System;
+use std::sync::atomic::Ordering;
+
+/// Size and call site of a merged record. The stack key hashes the frame
+/// addresses, which unlike the fingerprint don't change when a lazy record
+/// gets resolved.
+type SiteKey = (usize, u64);
+
+/// The allocations of a site.
+struct Site {
+    /// The address the record is kept under.
+    ptr: usize,
+    /// The other allocations merged into it.
+    members: Vec<usize, System>,
+}
+
+/// Which records stand for which allocations. Locked after the records.
+#[derive(Default)]
+pub(crate) struct Dedup {
+    sites: HashMap<SiteKey, Site, DefaultHashBuilder, System>,
+    /// The site of each merged allocation, and where it is in its members.
+    members: HashMap<usize, (SiteKey, usize), DefaultHashBuilder, System>,
+}
+
+impl Dedup {
+    pub fn clear(&mut self) {
+        self.sites.clear();
+        self.members.clear();
+    }
+
+    /// Whether `ptr` is merged into another allocation's record.
+    pub fn is_member(&self, ptr: usize) -> bool {
+        self.members.contains_key(&ptr)
+    }
+
+    /// How many allocations are merged into other allocations' records.
+    pub fn merged(&self) -> usize {
+        self.members.len()
+    }
+}
+
+fn site_key<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    record: &AllocationRecord<STACK_SIZE, NAME_LEN>,
+) -> SiteKey {
+    (record.size, record.stack_key)
+}
+
+/// Takes the allocation at `ptr` out, returning it as a record of its own.
+fn take<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    allocates: &mut Records<STACK_SIZE, NAME_LEN>,
+    dedup: &mut Dedup,
+    ptr: usize,
+) -> Option<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+    if let Some((key, index)) = dedup.members.remove(&ptr) {
+        let site = dedup.sites.get_mut(&key)?;
+        site.members.swap_remove(index);
+        if let Some(moved) = site.members.get(index) {
+            if let Some(member) = dedup.members.get_mut(moved) {
+                member.1 = index;
+            }
+        }
+        let representative = allocates.get_mut(&site.ptr)?;
+        representative.multiplicity -= 1;
+        return Some(AllocationRecord {
+            ptr,
+            multiplicity: 1,
+            ..representative.clone()
+        });
+    }
+
+    let mut record = allocates.remove(&ptr)?;
+    let key = site_key(&record);
+    let Some(site) = dedup.sites.get_mut(&key).filter(|site| site.ptr == ptr) else {
+        return Some(record);
+    };
+    match site.members.pop() {
+        // Keep the others under the address of one of them.
+        Some(heir) => {
+            dedup.members.remove(&heir);
+            site.ptr = heir;
+            let rest = AllocationRecord {
+                ptr: heir,
+                multiplicity: record.multiplicity - 1,
+                ..record.clone()
+            };
+            allocates.insert(heir, rest);
+        }
+        None => {
+            dedup.sites.remove(&key);
+        }
+    }
+    record.multiplicity = 1;
+    Some(record)
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    pub(crate) fn insert_deduplicated(&self, record: AllocationRecord<STACK_SIZE, NAME_LEN>) {
+        let key = site_key(&record);
+        let mut allocates = self.inner.allocates.lock();
+        let mut dedup = self.inner.dedup.lock();
+        // The address can be reused after a free we didn't see, e.g. while disabled.
+        if let Some(stale) = take(&mut allocates, &mut dedup, record.ptr) {
+            self.sub_live_bytes(stale.size);
+            self.account_group(&stale, false);
+        }
+        self.add_live_bytes(record.size);
+        self.account_group(&record, true);
+
+        let dedup = &mut *dedup;
+        let site = dedup.sites.get_mut(&key);
+        match site.and_then(|site| Some((allocates.get_mut(&site.ptr)?, site))) {
+            Some((representative, site)) => {
+                representative.multiplicity += 1;
+                dedup.members.insert(record.ptr, (key, site.members.len()));
+                site.members.push(record.ptr);
+            }
+            None => {
+                let site = Site {
+                    ptr: record.ptr,
+                    members: Vec::new_in(System),
+                };
+                if let Some(orphaned) = dedup.sites.insert(key, site) {
+                    for member in orphaned.members.iter() {
+                        dedup.members.remove(member);
+                    }
+                }
+                allocates.insert(record.ptr, record);
+            }
+        }
+        self.inner
+            .peak_live_allocations
+            .fetch_max(allocates.len() + dedup.merged(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove_deduplicated(
+        &self,
+        ptr: usize,
+    ) -> Option<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        let mut allocates = self.inner.allocates.lock();
+        let removed = take(&mut allocates, &mut self.inner.dedup.lock(), ptr);
+        if let Some(record) = &removed {
+            self.sub_live_bytes(record.size);
+            self.account_group(record, false);
+        }
+        removed
+    }
+
+    pub(crate) fn remove_many_deduplicated(&self, ptrs: &[usize]) -> usize {
+        let mut allocates = self.inner.allocates.lock();
+        let mut dedup = self.inner.dedup.lock();
+        let mut removed = 0;
+        for ptr in ptrs {
+            if let Some(record) = take(&mut allocates, &mut dedup, *ptr) {
+                self.sub_live_bytes(record.size);
+                self.account_group(&record, false);
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::frame_fn;
+    use crate::LeakTracer;
+    use std::alloc::{GlobalAlloc, Layout};
+
+    frame_fn! {
+        fn alloc_cache_entry(tracer: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+            unsafe { tracer.alloc(layout) }
+        }
+    }
+
+    #[test]
+    fn identical_allocations_collapse_into_one_record() {
+        let tracer = LeakTracer::<8>::builder().deduplicate(true).build();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptrs: Vec<_> = (0..1000)
+            .map(|_| alloc_cache_entry(&tracer, layout))
+            .collect();
+        let other = alloc_cache_entry(&tracer, Layout::from_size_align(128, 8).unwrap());
+
+        let records = tracer.get_leaks();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&(ptrs[0] as usize)].multiplicity, 1000);
+        assert_eq!(records[&(other as usize)].multiplicity, 1);
+        assert_eq!(tracer.stats().live_allocations, 1001);
+        assert_eq!(tracer.live_bytes(), 1000 * 64 + 128);
+
+        // Freeing the one the record is kept under hands it to another.
+        unsafe { tracer.dealloc(ptrs[0], layout) };
+        let records = tracer.get_leaks();
+        let merged = records.values().find(|record| record.size == 64).unwrap();
+        assert_eq!(merged.multiplicity, 999);
+        assert_ne!(merged.ptr, ptrs[0] as usize);
+        assert!(!tracer.is_live(ptrs[0] as usize));
+        assert!(tracer.is_live(ptrs[1] as usize));
+        assert!(tracer.is_live(ptrs[999] as usize));
+
+        for ptr in &ptrs[1..] {
+            unsafe { tracer.dealloc(*ptr, layout) };
+        }
+        unsafe { tracer.dealloc(other, Layout::from_size_align(128, 8).unwrap()) };
+        assert!(tracer.is_empty());
+        assert_eq!(tracer.live_bytes(), 0);
+    }
+
+    #[test]
+    fn aggregations_count_every_merged_allocation() {
+        let tracer = LeakTracer::<8>::builder().deduplicate(true).build();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let mut ptrs: Vec<_> = (0..10)
+            .map(|_| alloc_cache_entry(&tracer, layout))
+            .collect();
+        let other = Layout::from_size_align(128, 8).unwrap();
+        let single = alloc_cache_entry(&tracer, other);
+        assert_eq!(tracer.peak_live_allocations(), 11);
+
+        let report = tracer.report();
+        let mut groups: Vec<_> = report
+            .groups()
+            .map(|group| (group.count, group.bytes))
+            .collect();
+        groups.sort();
+        assert_eq!(groups, [(1, 128), (10, 640)]);
+        assert!(tracer
+            .leak_summary_json()
+            .contains(r#""count":10,"total_bytes":640"#));
+
+        assert_eq!(
+            tracer
+                .leaks_by_thread()
+                .values()
+                .copied()
+                .collect::<Vec<_>>(),
+            [(11, 768)]
+        );
+        assert_eq!(
+            tracer
+                .leaks_by_label()
+                .values()
+                .copied()
+                .collect::<Vec<_>>(),
+            [(11, 768)]
+        );
+        let mut top_frames: Vec<_> = tracer.leaks_by_top_frame().values().copied().collect();
+        top_frames.sort();
+        assert_eq!(top_frames, [(11, 768)]);
+        assert_eq!(tracer.leak_bytes_for_symbol("alloc_cache_entry"), 768);
+        assert_eq!(tracer.count_by_size_bucket()[0], ("0-64", 10, 640));
+        assert_eq!(tracer.age_histogram()[0].1, 11);
+        let mut grown: Vec<_> = tracer
+            .growing_sites()
+            .iter()
+            .map(|site| site.current)
+            .collect();
+        grown.sort();
+        assert_eq!(grown, [1, 10]);
+
+        // Handing the record from one allocation to the next, all the way down.
+        for ptr in ptrs.drain(..) {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        unsafe { tracer.dealloc(single, other) };
+        assert!(tracer.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! Catching blocks freed twice, see
+//! [`LeakTracerBuilder::on_double_free`](crate::LeakTracerBuilder::on_double_free).
+
+use crate::{DoubleFree, LeakTracer};
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashSet;
+use std::alloc::System;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+
+/// How many of the last freed tracked blocks are remembered to catch a
+/// second free of.
+pub const DOUBLE_FREE_WINDOW: usize = 4096;
+
+/// The tracked blocks freed lately and not handed out again.
+pub(crate) struct FreedBlocks {
+    ptrs: HashSet<usize, DefaultHashBuilder, System>,
+    // Oldest first, to forget them past the window. May still hold blocks
+    // handed out again since.
+    order: VecDeque<usize, System>,
+}
+
+impl Default for FreedBlocks {
+    fn default() -> Self {
+        Self {
+            ptrs: HashSet::default(),
+            order: VecDeque::new_in(System),
+        }
+    }
+}
+
+impl FreedBlocks {
+    fn insert(&mut self, ptr: usize) {
+        if self.order.len() == DOUBLE_FREE_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ptrs.remove(&oldest);
+            }
+        }
+        self.ptrs.insert(ptr);
+        self.order.push_back(ptr);
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Forgets that `ptr` was freed, now that it's handed out again.
+    pub(crate) fn reused_block(&self, ptr: *mut u8) {
+        if self.options.on_double_free != DoubleFree::Ignore {
+            self.inner.freed.lock().ptrs.remove(&(ptr as usize));
+        }
+    }
+
+    /// Remembers that the tracked block at `ptr` was freed.
+    pub(crate) fn freed_block(&self, ptr: *mut u8) {
+        if self.options.on_double_free != DoubleFree::Ignore {
+            self.inner.freed.lock().insert(ptr as usize);
+        }
+    }
+
+    /// Whether freeing the untracked `ptr` is a double free, in which case it
+    /// mustn't reach `System`. Acts on it as configured.
+    pub(crate) fn is_double_free(&self, ptr: *mut u8, size: usize) -> bool {
+        if self.options.on_double_free == DoubleFree::Ignore
+            || !self.inner.freed.lock().ptrs.contains(&(ptr as usize))
+        {
+            return false;
+        }
+        self.inner.double_frees.fetch_add(1, Ordering::Relaxed);
+        match self.options.on_double_free {
+            DoubleFree::Ignore | DoubleFree::Record => {}
+            DoubleFree::Log => {
+                let _ = writeln!(
+                    std::io::stderr(),
+                    "leak-detect-allocator: double free of {ptr:p} ({size} bytes)"
+                );
+            }
+            DoubleFree::Abort => {
+                let _ = writeln!(
+                    std::io::stderr(),
+                    "leak-detect-allocator: double free of {ptr:p} ({size} bytes), aborting"
+                );
+                std::process::abort();
+            }
+        }
+        true
+    }
+
+    /// How many double frees were caught, see
+    /// [`LeakTracerBuilder::on_double_free`](crate::LeakTracerBuilder::on_double_free).
+    pub fn double_frees(&self) -> usize {
+        self.inner.double_frees.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DoubleFree, LeakTracer};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn second_frees_are_counted_and_swallowed() {
+        let tracer = LeakTracer::<4>::builder()
+            .on_double_free(DoubleFree::Record)
+            .build();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        unsafe { tracer.dealloc(ptr, layout) };
+        assert_eq!(tracer.double_frees(), 0);
+
+        // Not handed to `System`, or this would corrupt its heap.
+        unsafe { tracer.dealloc(ptr, layout) };
+        assert_eq!(tracer.double_frees(), 1);
+
+        // Once an address is handed out again, freeing it is fine. Made up
+        // addresses, since `System` may not reuse one when asked.
+        let (reused, freed) = (0x1000 as *mut u8, 0x2000 as *mut u8);
+        tracer.freed_block(reused);
+        tracer.freed_block(freed);
+        tracer.reused_block(reused);
+        assert!(!tracer.is_double_free(reused, 48));
+        assert_eq!(tracer.double_frees(), 1);
+        assert!(tracer.is_double_free(freed, 48));
+        assert_eq!(tracer.double_frees(), 2);
+    }
+
+    #[test]
+    fn frees_of_skipped_allocations_are_not_double_frees() {
+        let tracer = LeakTracer::<4>::builder()
+            .on_double_free(DoubleFree::Record)
+            .min_size(16)
+            .adaptive_sampling(64, 1)
+            .build();
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let sampled = Layout::from_size_align(32, 8).unwrap();
+        // Recorded and skipped blocks take turns at the same addresses.
+        let moved = Layout::from_size_align(48, 8).unwrap();
+        for _ in 0..1000 {
+            for layout in [small, sampled].iter() {
+                let ptr = unsafe { tracer.alloc(*layout) };
+                unsafe { tracer.dealloc(ptr, *layout) };
+            }
+            let ptr = unsafe { tracer.alloc(sampled) };
+            let ptr = unsafe { tracer.realloc(ptr, sampled, moved.size()) };
+            unsafe { tracer.dealloc(ptr, moved) };
+        }
+        assert!(tracer.sample_interval() > 1);
+        assert_eq!(tracer.double_frees(), 0);
+        assert!(tracer.is_empty());
+    }
+}
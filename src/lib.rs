@@ -1,45 +1,127 @@
-#![feature(new_uninit, allocator_api)]
+#![feature(allocator_api, thread_id_value)]
 use backtrace::{BytesOrWideString, Symbol};
 use hashbrown::hash_map::DefaultHashBuilder;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use heapless::String as HeaplessString;
 use heapless::Vec as HeaplessVec;
 use once_cell::sync::Lazy;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::fmt::Display;
-use std::sync::atomic::AtomicBool;
+use std::any::TypeId;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::{Display, Write as _};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use widestring::U16Str;
 
+#[cfg(target_os = "linux")]
+mod debug_file;
+mod dedup;
+mod double_free;
+mod macros;
+mod module;
+#[cfg(feature = "owned-strings")]
+mod owned;
+mod report;
+mod resolver;
+mod snapshot_file;
+mod store;
+mod watcher;
+
+pub use double_free::DOUBLE_FREE_WINDOW;
+#[doc(hidden)]
+pub use macros::at_exit as __at_exit;
+#[cfg(feature = "owned-strings")]
+pub use owned::{OwnedCall, OwnedRecord, SystemString};
+pub use report::{LeakGroup, LeakReport, TopGroups};
+pub use resolver::ResolverHandle;
+pub use snapshot_file::SnapshotFile;
+pub use store::LockKind;
+use store::RecordLock;
+pub use watcher::{WatchSummary, WatcherHandle};
+
+/// Default capacity of [`Call::name`] and [`Call::filename`], longer strings
+/// are truncated.
+pub const DEFAULT_NAME_LEN: usize = 500;
+
 #[derive(Debug, Clone)]
-pub struct Call {
-    pub name: Option<HeaplessString<500>>,
-    pub filename: Option<HeaplessString<500>>,
+pub struct Call<const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    pub name: Option<HeaplessString<NAME_LEN>>,
+    pub filename: Option<HeaplessString<NAME_LEN>>,
     pub line: Option<u32>,
     pub col: Option<u32>,
 
     pub addr: usize,
+    /// Return address of the frame this was resolved from, 0 if unknown.
+    pub ip: usize,
+    /// With [`ResolveMode::ModuleOffset`], the index in
+    /// [`LeakTracer::modules`] of the module the frame is in.
+    pub module: Option<usize>,
+    /// And where in that module, `ip` minus its load address.
+    pub offset: usize,
+}
+
+/// Calls compare by where they point in the source, or in their module;
+/// `addr` and `ip` are ignored since they change between runs (ASLR).
+impl<const NAME_LEN: usize> PartialEq for Call<NAME_LEN> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.filename == other.filename
+            && self.line == other.line
+            && self.col == other.col
+            && self.module == other.module
+            && self.offset == other.offset
+    }
+}
+
+impl<const NAME_LEN: usize> Eq for Call<NAME_LEN> {}
+
+impl<const NAME_LEN: usize> Hash for Call<NAME_LEN> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.as_ref().map(|x| x.as_str()).hash(state);
+        self.filename.as_ref().map(|x| x.as_str()).hash(state);
+        self.line.hash(state);
+        self.col.hash(state);
+        self.module.hash(state);
+        self.offset.hash(state);
+    }
+}
+
+impl<const NAME_LEN: usize> Call<NAME_LEN> {
+    /// Whether `needle` is part of the symbol name or the filename.
+    pub fn matches(&self, needle: &str) -> bool {
+        self.name.as_ref().is_some_and(|name| name.contains(needle))
+            || self
+                .filename
+                .as_ref()
+                .is_some_and(|filename| filename.contains(needle))
+    }
 }
 
-impl From<&Symbol> for Call {
+impl<const NAME_LEN: usize> From<&Symbol> for Call<NAME_LEN> {
+    /// Names longer than `NAME_LEN` bytes are truncated. Filenames lose their
+    /// leading directories instead, see [`truncated_path`].
     fn from(value: &Symbol) -> Self {
-        let addr = value.addr().unwrap() as usize;
+        let addr = value.addr().map_or(0, |x| x as usize);
         let line = value.lineno();
         let col = value.colno();
-        let name = value
-            .name()
-            .map(|x| HeaplessString::from(x.as_str().unwrap()));
+        let name = value.name().and_then(|x| x.as_str()).map(truncated);
         let filename = value.filename_raw().map(|x| match x {
             BytesOrWideString::Bytes(bytes) => {
-                HeaplessString::from(std::str::from_utf8(bytes).unwrap())
+                truncated_path(bytes.utf8_chunks().flat_map(|chunk| {
+                    let invalid =
+                        (!chunk.invalid().is_empty()).then_some(char::REPLACEMENT_CHARACTER);
+                    chunk.valid().chars().chain(invalid)
+                }))
             }
             BytesOrWideString::Wide(bytes) => {
-                let mut filename = HeaplessString::default();
-                for c in U16Str::from_slice(bytes).chars_lossy() {
-                    filename.push(c).unwrap();
-                }
-                filename
+                truncated_path(U16Str::from_slice(bytes).chars_lossy())
             }
         });
 
@@ -49,27 +131,474 @@ impl From<&Symbol> for Call {
             line,
             col,
             addr,
+            ip: 0,
+            module: None,
+            offset: 0,
+        }
+    }
+}
+
+/// Names registered with [`LeakTracer::register_symbol`], sorted by address.
+type Symbols<const NAME_LEN: usize> = Vec<(usize, HeaplessString<NAME_LEN>), System>;
+
+fn resolve_frames<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    frames: &[usize],
+    symbols: Option<&Symbols<NAME_LEN>>,
+) -> HeaplessVec<Call<NAME_LEN>, STACK_SIZE> {
+    let mut stack = HeaplessVec::new();
+    for ip in frames {
+        let from = stack.len();
+        unsafe {
+            backtrace::resolve_unsynchronized(*ip as *mut std::ffi::c_void, |symbol| {
+                let _ = stack.push(Call {
+                    ip: *ip,
+                    ..symbol.into()
+                });
+            });
+        }
+        if let Some(symbols) = symbols {
+            name_from_registered(&mut stack, from, *ip, symbols, STACK_SIZE);
+        }
+        if stack.is_full() {
+            break;
+        }
+    }
+    stack
+}
+
+/// Names the calls `stack[from..]` of the frame at `ip` from `symbols` if
+/// none of them got a name, adding one if there are none and fewer than
+/// `limit` calls. The symbol used is the closest at or below `ip`.
+fn name_from_registered<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    stack: &mut HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+    from: usize,
+    ip: usize,
+    symbols: &Symbols<NAME_LEN>,
+    limit: usize,
+) {
+    if stack[from..].iter().any(|call| call.name.is_some()) {
+        return;
+    }
+    let Some((addr, name)) = symbols
+        .partition_point(|(addr, _)| *addr <= ip)
+        .checked_sub(1)
+        .map(|idx| &symbols[idx])
+    else {
+        return;
+    };
+    let addr = *addr;
+    if stack.len() == from {
+        if stack.len() < limit {
+            let _ = stack.push(Call {
+                name: None,
+                filename: None,
+                line: None,
+                col: None,
+                addr,
+                ip,
+                module: None,
+                offset: 0,
+            });
+        } else {
+            return;
+        }
+    }
+    for call in stack[from..].iter_mut() {
+        call.name = Some(name.clone());
+        if call.addr == 0 {
+            call.addr = addr;
+        }
+    }
+}
+
+/// Thread names longer than this are truncated in [`AllocationRecord::thread_name`].
+pub const THREAD_NAME_LEN: usize = 64;
+
+/// Copies as much of `s` as fits, cutting at a char boundary.
+fn truncated<const N: usize>(s: &str) -> HeaplessString<N> {
+    truncated_chars(s.chars())
+}
+
+fn truncated_chars<const N: usize>(chars: impl Iterator<Item = char>) -> HeaplessString<N> {
+    let mut out = HeaplessString::new();
+    for c in chars {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+/// Keeps the end of a path too long for `N` bytes, where the basename is,
+/// behind a `...`. The cut is moved to a directory separator when that still
+/// leaves something.
+fn truncated_path<const N: usize>(chars: impl Iterator<Item = char>) -> HeaplessString<N> {
+    const ELLIPSIS: &str = "...";
+    // Only the last `N` chars can end up in the result, each taking a byte at least.
+    let mut tail = ['\0'; N];
+    let mut count = 0;
+    let mut bytes = 0;
+    for c in chars {
+        if N > 0 {
+            tail[count % N] = c;
+        }
+        count += 1;
+        bytes += c.len_utf8();
+    }
+    let kept = (count.saturating_sub(N)..count).map(|i| tail[i % N]);
+    if bytes <= N {
+        return truncated_chars(kept);
+    }
+
+    let budget = N.saturating_sub(ELLIPSIS.len());
+    let mut start = count;
+    let mut used = 0;
+    while start > count.saturating_sub(N) {
+        let len = tail[(start - 1) % N].len_utf8();
+        if used + len > budget {
+            break;
+        }
+        used += len;
+        start -= 1;
+    }
+    if let Some(separator) = (start..count - 1).find(|i| matches!(tail[i % N], '/' | '\\')) {
+        start = separator;
+    }
+    truncated_chars(ELLIPSIS.chars().chain((start..count).map(|i| tail[i % N])))
+}
+
+/// How many records [`LeakTracer::resolve_all`] resolves between two
+/// progress reports.
+pub const RESOLVE_PROGRESS_STEP: usize = 32;
+
+/// Labels longer than this are truncated, see [`LeakTracer::push_label`].
+pub const LABEL_LEN: usize = 64;
+
+/// How many labels can be nested on one thread, deeper ones are ignored.
+pub const MAX_LABEL_DEPTH: usize = 16;
+
+/// Resumes tracking when dropped, see [`LeakTracer::disable_scope`].
+#[must_use = "tracking resumes as soon as the guard is dropped"]
+pub struct DisableGuard<'a> {
+    depth: &'a AtomicUsize,
+}
+
+impl Drop for DisableGuard<'_> {
+    fn drop(&mut self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Disarms the thread armed by [`LeakTracer::arm_current_thread`] when
+/// dropped, unless it was armed already.
+#[must_use = "the thread is disarmed as soon as the guard is dropped"]
+pub struct ThreadArmGuard {
+    was_armed: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ThreadArmGuard {
+    fn drop(&mut self) {
+        let _ = ARMED.try_with(|armed| armed.set(self.was_armed));
+    }
+}
+
+/// Pops the label pushed by [`LeakTracer::push_label`] when dropped.
+#[must_use = "the label is popped as soon as the guard is dropped"]
+pub struct LabelGuard {
+    pushed: bool,
+    // The label stack is per thread, the guard must be dropped where it was made.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for LabelGuard {
+    fn drop(&mut self) {
+        if self.pushed {
+            let _ = LABELS.try_with(|labels| labels.borrow_mut().pop());
         }
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct AllocationRecord<const STACK_SIZE: usize> {
+pub struct AllocationRecord<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    /// Bytes requested by the allocation.
     pub size: usize,
+    /// Bytes the system allocator actually provided, see [`usable_size`].
+    pub usable_size: usize,
+    /// Alignment the block was allocated with, kept across reallocations.
+    pub align: usize,
     pub ptr: usize,
-    pub stack: HeaplessVec<Call, STACK_SIZE>,
+    pub stack: HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+    /// Return addresses of the captured frames, outermost last. Each
+    /// [`Call::ip`] in `stack` is one of them.
+    pub frames: HeaplessVec<usize, STACK_SIZE>,
+    /// Whether `stack` was filled in. Until then only `frames` is.
+    pub symbolized: bool,
+    /// Id of the thread that made the allocation, see [`std::thread::ThreadId::as_u64`].
+    pub thread_id: u64,
+    pub thread_name: Option<HeaplessString<THREAD_NAME_LEN>>,
+    /// The innermost label pushed with [`LeakTracer::push_label`] on the
+    /// allocating thread.
+    pub label: Option<HeaplessString<LABEL_LEN>>,
+    /// Order in which the allocation was recorded, starting at 1.
+    pub seq: u64,
+    /// When the allocation was made, by the tracer's clock (see
+    /// [`LeakTracer::set_clock`]).
+    pub allocated_at: Duration,
+    /// Which call produced the block, `Realloc` once it was reallocated.
+    pub origin: AllocOrigin,
+    /// How many times the block was reallocated since. `stack` still is
+    /// where it was first allocated.
+    pub realloc_count: u32,
+    /// How many live allocations the record stands for, more than one only
+    /// with [`LeakTracerBuilder::deduplicate`].
+    pub multiplicity: usize,
+    /// Hash of `frames` taken at capture (of `stack` when no frame was
+    /// captured), see [`LeakTracer::leaks_grouped_by_stack`]. Unlike the
+    /// fingerprint it doesn't wait for symbolization, but changes between runs.
+    pub stack_key: u64,
+    /// Where the block was last moved by a reallocation, with
+    /// [`LeakTracerBuilder::last_realloc_stack`]. Always symbolized.
+    pub last_realloc_stack: Option<Box<HeaplessVec<Call<NAME_LEN>, STACK_SIZE>, System>>,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> AllocationRecord<STACK_SIZE, NAME_LEN> {
+    /// Formats the record like `Display`, with the given options.
+    pub fn display_with(&self, options: ReportOptions) -> impl Display + '_ {
+        RecordDisplay {
+            record: self,
+            options,
+        }
+    }
+
+    /// Symbolizes `frames` into `stack` if that wasn't done yet, see
+    /// [`ResolveMode::Lazy`].
+    pub fn resolve(&mut self) {
+        if !self.symbolized {
+            self.stack = resolve_frames(&self.frames, None);
+            self.symbolized = true;
+        }
+    }
+
+    /// The first frame outside the allocator machinery (this tracer's
+    /// `GlobalAlloc` impl and the `__rust_alloc` shims), which is usually the
+    /// function that asked for the memory.
+    pub fn top_frame(&self) -> Option<&Call<NAME_LEN>> {
+        self.stack.iter().find(|call| !is_allocator_frame(call))
+    }
+
+    /// The first frame whose symbol name or filename contains `root`, see
+    /// [`LeakTracerBuilder::attribution_root`].
+    pub fn attribution_frame(&self, root: &str) -> Option<&Call<NAME_LEN>> {
+        self.stack.iter().find(|call| call.matches(root))
+    }
+
+    /// A hash identifying the call site (the symbolized stack) of this
+    /// allocation. Addresses are left out so it's stable across runs, except
+    /// for records not resolved yet in [`ResolveMode::Lazy`].
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        if !self.symbolized {
+            // Not stable across runs, but still tells sites apart.
+            self.frames.hash(&mut hasher);
+        }
+        for call in self.stack.iter() {
+            call.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// [`fingerprint`](Self::fingerprint) as 16 hex digits, to refer to a
+    /// call site in logs and reports.
+    pub fn fingerprint_hex(&self) -> HeaplessString<16> {
+        let mut hex = HeaplessString::new();
+        // 16 digits always fit.
+        let _ = write!(hex, "{:016x}", self.fingerprint());
+        hex
+    }
+}
+
+/// [`AllocationRecord::stack_key`] of a captured stack.
+fn stack_key<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    frames: &HeaplessVec<usize, STACK_SIZE>,
+    stack: &HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+) -> u64 {
+    let mut hasher = FnvHasher::default();
+    if frames.is_empty() {
+        // Nothing captured, the stack is empty or a sentinel for good.
+        for call in stack.iter() {
+            call.hash(&mut hasher);
+        }
+    } else {
+        frames.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn is_allocator_frame<const NAME_LEN: usize>(call: &Call<NAME_LEN>) -> bool {
+    let Some(name) = &call.name else {
+        return false;
+    };
+    name.contains("__rust_alloc")
+        || name.contains("__rust_realloc")
+        || name.contains("__rg_")
+        || (name.contains("LeakTracer") && name.contains("GlobalAlloc"))
+}
+
+/// Records compare by size and stack only. Where the block lives (`ptr`) and
+/// which thread made it don't take part, so two allocations from the same
+/// site are equal; use [`AllocationRecord::fingerprint`] to ignore the size too.
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> PartialEq
+    for AllocationRecord<STACK_SIZE, NAME_LEN>
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size && self.stack == other.stack
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Eq for AllocationRecord<STACK_SIZE, NAME_LEN> {}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Hash
+    for AllocationRecord<STACK_SIZE, NAME_LEN>
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.size.hash(state);
+        for call in self.stack.iter() {
+            call.hash(state);
+        }
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Display
+    for AllocationRecord<STACK_SIZE, NAME_LEN>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.display_with(ReportOptions::new()).fmt(f)
+    }
+}
+
+/// How records are printed, see [`AllocationRecord::display_with`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReportOptions {
+    verbose: bool,
+    collapse_recursion: bool,
+    strip_addresses: bool,
+}
+
+impl ReportOptions {
+    pub const fn new() -> Self {
+        Self {
+            verbose: false,
+            collapse_recursion: false,
+            strip_addresses: false,
+        }
+    }
+
+    /// Leave out every address and offset, unresolved frames included, and
+    /// list leaks in allocation order, so that reports of the same run come
+    /// out byte for byte the same on any machine. Meant for golden-file
+    /// tests. Overrides `verbose`. Off by default.
+    pub const fn strip_addresses(mut self, strip: bool) -> Self {
+        self.strip_addresses = strip;
+        self
+    }
+
+    /// Print a run of identical consecutive frames, as left by recursion,
+    /// once with ` (xN)` appended. Off by default.
+    pub const fn collapse_recursion(mut self, collapse: bool) -> Self {
+        self.collapse_recursion = collapse;
+        self
+    }
+
+    /// Also print where each frame is in its binary, as `name+0xoffset
+    /// (module)`, or `name (module+0xoffset)` when the symbol isn't exported,
+    /// to symbolize stripped binaries by hand. Off by default.
+    pub const fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+}
+
+struct RecordDisplay<'a, const STACK_SIZE: usize, const NAME_LEN: usize> {
+    record: &'a AllocationRecord<STACK_SIZE, NAME_LEN>,
+    options: ReportOptions,
 }
 
-impl<const STACK_SIZE: usize> Display for AllocationRecord<STACK_SIZE> {
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Display
+    for RecordDisplay<'_, STACK_SIZE, NAME_LEN>
+{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Allocation (size {}):\n", self.size)?;
-        for s in self.stack.iter() {
+        let record = self.record;
+        write!(f, "Allocation (size {}, ", record.size)?;
+        match &record.thread_name {
+            Some(thread) => write!(f, "thread {thread}")?,
+            None => write!(f, "thread #{}", record.thread_id)?,
+        }
+        if let Some(label) = &record.label {
+            write!(f, ", label {label}")?;
+        }
+        if record.multiplicity > 1 {
+            write!(f, ", {} allocations", record.multiplicity)?;
+        }
+        if record.realloc_count > 0 {
+            write!(f, ", reallocated {} times", record.realloc_count)?;
+        } else if record.origin == AllocOrigin::Realloc {
+            write!(f, ", from realloc")?;
+        }
+        writeln!(f, "):")?;
+        if !record.symbolized {
+            for ip in record.frames.iter() {
+                if self.options.strip_addresses {
+                    writeln!(f, "  [unresolved]")?;
+                } else {
+                    writeln!(f, "  {ip:#x}")?;
+                }
+            }
+        }
+        self.write_stack(f, &record.stack)?;
+        if let (true, Some(moved)) = (self.options.verbose, &record.last_realloc_stack) {
+            writeln!(f, "  last moved by realloc at:")?;
+            self.write_stack(f, moved)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> RecordDisplay<'_, STACK_SIZE, NAME_LEN> {
+    fn write_stack(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        stack: &[Call<NAME_LEN>],
+    ) -> std::fmt::Result {
+        let mut frames = stack.iter().peekable();
+        while let Some(s) = frames.next() {
+            let mut repeats = 1;
+            if self.options.collapse_recursion {
+                while frames.next_if(|next| *next == s).is_some() {
+                    repeats += 1;
+                }
+            }
+            if let (None, Some(module)) = (&s.name, s.module) {
+                write!(f, "  [module {module}]")?;
+                if !self.options.strip_addresses {
+                    write!(f, "+{:#x}", s.offset)?;
+                }
+                if repeats > 1 {
+                    write!(f, " (x{repeats})")?;
+                }
+                writeln!(f)?;
+                continue;
+            }
             let name = s.name.clone().unwrap_or(HeaplessString::from("[unknown]"));
             let filename = s
                 .filename
                 .clone()
                 .unwrap_or(HeaplessString::from("[unknown file]"));
-            write!(f, "  {name} @ {filename}")?;
+            write!(f, "  {name}")?;
+            if self.options.verbose && !self.options.strip_addresses {
+                write_location(f, s.ip)?;
+            }
+            write!(f, " @ {filename}")?;
             match (s.line, s.col) {
                 (Some(line), Some(col)) => write!(f, ":{line}-{col})")?,
                 (Some(line), _) => write!(f, ":{line}")?,
@@ -77,134 +606,5070 @@ impl<const STACK_SIZE: usize> Display for AllocationRecord<STACK_SIZE> {
                 // Normally this should not happen, so it should be safe to ignore that.
                 (_, _) => {}
             };
-            write!(f, "\n")?;
+            if repeats > 1 {
+                write!(f, " (x{repeats})")?;
+            }
+            writeln!(f)?;
         }
         Ok(())
     }
 }
 
-pub struct LeakTracerInner<const STACK_SIZE: usize> {
-    allocates: Mutex<HashMap<usize, AllocationRecord<STACK_SIZE>, DefaultHashBuilder, System>>,
-    enabled: AtomicBool,
+/// Writes `+0xoffset (module)` for `ip`, or what of it is known.
+fn write_location(f: &mut std::fmt::Formatter<'_>, ip: usize) -> std::fmt::Result {
+    let Some(module) = (ip != 0).then(|| module::lookup(ip)).flatten() else {
+        return Ok(());
+    };
+    let name = module.name().unwrap_or("[unknown module]");
+    match module.symbol_addr {
+        Some(symbol) if symbol <= ip => write!(f, "+{:#x} ({name})", ip - symbol),
+        _ => write!(f, " ({name}+{:#x})", ip - module.base),
+    }
+}
+
+/// Which allocator call produced a block, see [`AllocationRecord::origin`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AllocOrigin {
+    Alloc,
+    /// A reallocation, of a tracked block (its record is carried over) or of
+    /// one allocated while tracking was off.
+    Realloc,
 }
 
-impl<const STACK_SIZE: usize> Default for LeakTracerInner<STACK_SIZE> {
+/// FNV-1a, used instead of the map hasher where the hash must not change
+/// between runs.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
     fn default() -> Self {
-        Self {
-            allocates: Mutex::new(HashMap::default()),
-            enabled: AtomicBool::new(true),
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
         }
     }
 }
 
-pub struct LeakTracer<const STACK_SIZE: usize>(Lazy<LeakTracerInner<STACK_SIZE>>);
+/// A call site whose number of live allocations grew between two calls to
+/// [`LeakTracer::growing_sites`].
+#[derive(Debug, Clone)]
+pub struct GrowingSite<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    pub fingerprint: u64,
+    pub previous: usize,
+    pub current: usize,
+    /// One of the live allocations from this site.
+    pub sample: AllocationRecord<STACK_SIZE, NAME_LEN>,
+}
 
-pub type LeakTracerDefault = LeakTracer<10>;
+/// Totals over the live allocations, and reallocation churn, see
+/// [`LeakTracer::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub live_allocations: usize,
+    /// Bytes requested by the live allocations.
+    pub live_bytes: usize,
+    /// Bytes actually provided for them, the difference to `live_bytes` is
+    /// the allocator's internal fragmentation.
+    pub live_usable_bytes: usize,
+    /// See [`LeakTracer::alloc_count`].
+    pub alloc_count: u64,
+    /// See [`LeakTracer::free_count`].
+    pub free_count: u64,
+    /// Bytes added by reallocations that grew a block, since the start.
+    pub realloc_grow_bytes: usize,
+    /// Bytes given back by reallocations that shrank a block.
+    pub realloc_shrink_bytes: usize,
+}
 
-impl<const STACK_SIZE: usize> LeakTracer<STACK_SIZE> {
-    pub const fn new() -> Self {
-        Self(Lazy::new(|| LeakTracerInner::default()))
+#[cfg(any(target_os = "linux", target_os = "android"))]
+extern "C" {
+    fn malloc_usable_size(ptr: *mut std::ffi::c_void) -> usize;
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+extern "C" {
+    fn malloc_size(ptr: *const std::ffi::c_void) -> usize;
+}
+
+/// How many bytes `System` really provided for an allocation of `size`
+/// bytes at `ptr`. Where the platform can't tell, this is just `size`.
+pub fn usable_size(ptr: *mut u8, size: usize) -> usize {
+    if ptr.is_null() {
+        return size;
     }
+    // `System` goes through malloc on these platforms, whatever the alignment.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return unsafe { malloc_usable_size(ptr as _) }.max(size);
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    return unsafe { malloc_size(ptr as _) }.max(size);
+    #[allow(unreachable_code)]
+    size
+}
 
-    pub fn disable(&self) {
-        self.0.enabled.store(false, Ordering::SeqCst);
+/// Inclusive upper bound and label of each bucket of
+/// [`LeakTracer::count_by_size_bucket`]. The last one takes everything bigger.
+const SIZE_BUCKETS: [(usize, &str); 7] = [
+    (64, "0-64"),
+    (256, "65-256"),
+    (1024, "257-1024"),
+    (4096, "1025-4096"),
+    (16384, "4097-16384"),
+    (65536, "16385-65536"),
+    (usize::MAX, "65537+"),
+];
+
+/// Upper bounds (exclusive) of the ranges [`LeakTracer::age_histogram`]
+/// counts in.
+const AGE_BUCKETS: [(Duration, &str); 4] = [
+    (Duration::from_secs(1), "<1s"),
+    (Duration::from_secs(10), "1-10s"),
+    (Duration::from_secs(60), "10-60s"),
+    (Duration::MAX, ">60s"),
+];
+
+/// One class per power of two a size can be rounded up to, 1 to `2^63`, and
+/// one for bigger sizes.
+const SIZE_CLASSES: usize = usize::BITS as usize + 1;
+
+/// Index of the smallest power of two at least `size`, 0 for 0 and 1.
+fn size_class(size: usize) -> usize {
+    match size {
+        0 | 1 => 0,
+        _ => (usize::BITS - (size - 1).leading_zeros()) as usize,
     }
+}
 
-    pub fn enable(&self) {
-        self.0.enabled.store(true, Ordering::SeqCst);
+/// Largest size in `class`, see [`LeakTracer::bytes_by_size_class`].
+fn class_size(class: usize) -> usize {
+    1usize.checked_shl(class as u32).unwrap_or(usize::MAX)
+}
+
+/// Adds `record` to its class's `(count, bytes)`.
+fn count_size_class<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    classes: &mut [(usize, usize); SIZE_CLASSES],
+    record: &AllocationRecord<STACK_SIZE, NAME_LEN>,
+) {
+    let class = &mut classes[size_class(record.size)];
+    class.0 += record.multiplicity;
+    class.1 += record.size * record.multiplicity;
+}
+
+/// The non-empty `classes` as `(class, count, bytes)`.
+fn size_class_totals(classes: &[(usize, usize); SIZE_CLASSES]) -> Vec<(usize, usize, usize)> {
+    classes
+        .iter()
+        .enumerate()
+        .filter(|(_, (count, _))| *count > 0)
+        .map(|(class, (count, bytes))| (class_size(class), *count, *bytes))
+        .collect()
+}
+
+/// The live totals of `records`.
+fn stats_of<'a, const STACK_SIZE: usize, const NAME_LEN: usize>(
+    records: impl Iterator<Item = &'a AllocationRecord<STACK_SIZE, NAME_LEN>>,
+) -> Stats {
+    let mut stats = Stats::default();
+    for record in records {
+        stats.live_allocations += record.multiplicity;
+        stats.live_bytes += record.size * record.multiplicity;
+        stats.live_usable_bytes += record.usable_size * record.multiplicity;
     }
+    stats
+}
 
-    pub fn get_leaks(
-        &self,
-    ) -> HashMap<usize, AllocationRecord<STACK_SIZE>, DefaultHashBuilder, System> {
-        let cur = self.0.enabled.load(Ordering::SeqCst);
-        self.0.enabled.store(false, Ordering::SeqCst);
+/// The records and stats of a tracer at one instant, see
+/// [`LeakTracer::snapshot_state`].
+#[derive(Clone)]
+pub struct TracerSnapshot<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    pub records: HashMap<usize, AllocationRecord<STACK_SIZE, NAME_LEN>, DefaultHashBuilder, System>,
+    pub stats: Stats,
+}
 
-        let mut out = HashMap::default();
-        for (k, v) in self.0.allocates.lock().iter() {
-            out.insert(*k, v.clone());
-        }
+/// The live allocations taken out of a tracer by [`LeakTracer::drain_leaks`].
+pub struct LeakSnapshot<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    records: HashMap<usize, AllocationRecord<STACK_SIZE, NAME_LEN>, DefaultHashBuilder, System>,
+}
 
-        self.0.enabled.store(cur, Ordering::SeqCst);
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakSnapshot<STACK_SIZE, NAME_LEN> {
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
 
-        out
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
     }
 
-    fn alloc_accounting(&self, size: usize, ptr: *mut u8) -> *mut u8 {
-        if !self.0.enabled.load(Ordering::SeqCst) {
-            return ptr;
-        }
+    pub fn total_bytes(&self) -> usize {
+        self.records
+            .values()
+            .map(|record| record.size * record.multiplicity)
+            .sum()
+    }
 
-        let mut stack = HeaplessVec::default();
-        let mut count = 0;
-        // First 2 stack is in the closure itself, meaningless, skip that.
-        let mut skip_count = 2;
-        // On win7 64, it's may cause deadlock, solution is to palce a newer version of dbghelp.dll combined with exe
-        unsafe {
-            backtrace::trace_unsynchronized(|frame| {
-                if skip_count > 0 {
-                    skip_count -= 1;
-                    return true;
-                }
+    /// Consumes the snapshot, returning the records largest first.
+    pub fn sorted_by_size(self) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        let mut records: Vec<_> = self.into_iter().collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.size));
+        records
+    }
+}
 
-                backtrace::resolve_frame_unsynchronized(frame, |symbol| {
-                    stack.push(symbol.into()).unwrap();
-                    count += 1;
-                });
-                if count >= STACK_SIZE {
-                    false
-                } else {
-                    true
-                }
-            });
-        }
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> IntoIterator
+    for LeakSnapshot<STACK_SIZE, NAME_LEN>
+{
+    type Item = AllocationRecord<STACK_SIZE, NAME_LEN>;
+    type IntoIter =
+        hashbrown::hash_map::IntoValues<usize, AllocationRecord<STACK_SIZE, NAME_LEN>, System>;
 
-        let allocation_record = AllocationRecord {
-            size,
-            ptr: ptr as usize,
-            stack,
-        };
-        self.0
-            .allocates
-            .lock()
-            .insert(ptr as usize, allocation_record);
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.into_values()
+    }
+}
 
-        ptr
+pub(crate) type Records<const STACK_SIZE: usize, const NAME_LEN: usize> =
+    HashMap<usize, AllocationRecord<STACK_SIZE, NAME_LEN>, DefaultHashBuilder, System>;
+
+/// The live records, locked until dropped, see [`LeakTracer::leaks_iter`].
+#[must_use = "the records are only locked to be iterated"]
+pub struct LeaksGuard<'a, const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    records: store::RecordGuard<'a, Records<STACK_SIZE, NAME_LEN>>,
+    _reentrancy: Option<ReentrancyGuard>,
+    // Untracked allocations are per thread, like the reentrancy flag.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, const STACK_SIZE: usize, const NAME_LEN: usize> LeaksGuard<'a, STACK_SIZE, NAME_LEN> {
+    pub fn iter(
+        &self,
+    ) -> hashbrown::hash_map::Iter<'_, usize, AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        self.records.iter()
     }
 
-    fn dealloc_accounting(&self, ptr: *mut u8) {
-        if !self.0.enabled.load(Ordering::SeqCst) {
-            return;
-        }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
 
-        self.0.allocates.lock().remove(&(ptr as usize));
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
     }
 }
 
-unsafe impl<const STACK_SIZE: usize> GlobalAlloc for LeakTracer<STACK_SIZE> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.alloc_accounting(layout.size(), System.alloc(layout))
+impl<'a, 'b, const STACK_SIZE: usize, const NAME_LEN: usize> IntoIterator
+    for &'b LeaksGuard<'a, STACK_SIZE, NAME_LEN>
+{
+    type Item = (&'b usize, &'b AllocationRecord<STACK_SIZE, NAME_LEN>);
+    type IntoIter = hashbrown::hash_map::Iter<'b, usize, AllocationRecord<STACK_SIZE, NAME_LEN>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
+}
 
-    unsafe fn realloc(&self, ptr0: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let ptr = System.realloc(ptr0, layout, new_size);
-        if ptr != ptr0 {
-            self.dealloc_accounting(ptr0);
-            self.alloc_accounting(new_size, ptr);
-        }
-        ptr
+/// A point in the allocation sequence, see [`LeakTracer::mark`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Marker {
+    seq: u64,
+}
+
+/// A tracked allocator event, delivered to the hook set by
+/// [`LeakTracer::set_event_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocEvent {
+    Alloc {
+        ptr: usize,
+        size: usize,
+    },
+    Free {
+        ptr: usize,
+        size: usize,
+    },
+    Realloc {
+        old_ptr: usize,
+        new_ptr: usize,
+        old_size: usize,
+        new_size: usize,
+    },
+}
+
+/// An event kept by [`LeakTracerBuilder::event_log`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedEvent {
+    /// Order of the event among all logged ones, starting at 1.
+    pub seq: u64,
+    pub event: AllocEvent,
+}
+
+type EventHook = Arc<dyn Fn(AllocEvent) + Send + Sync, System>;
+
+type AgeHook<const STACK_SIZE: usize, const NAME_LEN: usize> =
+    Arc<dyn Fn(&AllocationRecord<STACK_SIZE, NAME_LEN>) + Send + Sync, System>;
+
+#[cfg(any(test, feature = "test-util"))]
+type BacktraceProvider<const NAME_LEN: usize> =
+    Box<dyn Fn() -> Vec<Call<NAME_LEN>> + Send + Sync, System>;
+
+struct ThreadInfo {
+    id: u64,
+    name: Option<HeaplessString<THREAD_NAME_LEN>>,
+}
+
+thread_local! {
+    // Looked up once per thread, `thread::current()` may allocate.
+    static THREAD_INFO: OnceCell<ThreadInfo> = const { OnceCell::new() };
+    static LABELS: RefCell<HeaplessVec<HeaplessString<LABEL_LEN>, MAX_LABEL_DEPTH>> =
+        const { RefCell::new(HeaplessVec::new()) };
+    // Set while the tracer is doing its own work on this thread (capturing a
+    // backtrace, running a hook...), so allocations made by that work are not
+    // tracked and can't recurse into the tracer.
+    static IN_TRACER: Cell<bool> = const { Cell::new(false) };
+    // See `LeakTracerBuilder::armed_threads_only`.
+    static ARMED: Cell<bool> = const { Cell::new(false) };
+    // Set around the allocation made by `calibration_sentinel`.
+    static CALIBRATING: Cell<bool> = const { Cell::new(false) };
+}
+
+struct ReentrancyGuard;
+
+impl ReentrancyGuard {
+    /// Returns `None` if this thread is already inside the tracer (or its
+    /// thread locals are gone), in which case the caller must not track.
+    fn enter() -> Option<Self> {
+        IN_TRACER
+            .try_with(|inside| {
+                // Not `then_some`: building the guard eagerly and dropping it
+                // would clear the flag we are checking.
+                (!inside.replace(true)).then(|| ReentrancyGuard)
+            })
+            .ok()
+            .flatten()
     }
+}
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.dealloc_accounting(ptr);
-        System.dealloc(ptr, layout);
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        let _ = IN_TRACER.try_with(|inside| inside.set(false));
     }
 }
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        let _ = crate::LeakTracer::<15>::new();
+/// Frames of the tracer's own skipped at the top of a captured stack if
+/// [`LeakTracer::frame_skip`] couldn't be calibrated.
+const DEFAULT_FRAME_SKIP: usize = 3;
+
+/// The known caller [`LeakTracer::frame_skip`] is calibrated against. Frees
+/// through `System` so the tracer never sees the block go.
+#[inline(never)]
+fn calibration_sentinel<const STACK_SIZE: usize, const NAME_LEN: usize>(
+    tracer: &LeakTracer<STACK_SIZE, NAME_LEN>,
+) {
+    let layout = Layout::new::<usize>();
+    unsafe {
+        let ptr = tracer.alloc(layout);
+        if !ptr.is_null() {
+            System.dealloc(ptr, layout);
+        }
+    }
+}
+
+/// How long [`LeakTracer::alloc_rate`] counts before starting over.
+pub const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Time since the first call, the default clock of a tracer.
+fn monotonic_now() -> Duration {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed()
+}
+
+/// The tracer registered with [`LeakTracer::register_global`], with its type
+/// since the registry is shared by every `LeakTracer<..>`.
+static GLOBAL: OnceLock<(TypeId, usize)> = OnceLock::new();
+
+pub struct LeakTracerInner<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    allocates: RecordLock<
+        HashMap<usize, AllocationRecord<STACK_SIZE, NAME_LEN>, DefaultHashBuilder, System>,
+    >,
+    next_seq: AtomicU64,
+    // Sum of `size` (times `multiplicity`) over `allocates`, only changed
+    // with its lock held.
+    live_bytes: AtomicUsize,
+    // `live_bytes` split by power-of-two size class, see `size_class`.
+    class_bytes: [AtomicUsize; SIZE_CLASSES],
+    dedup: RecordLock<dedup::Dedup>,
+    // `(count, bytes)` of the live allocations by `stack_key`, see
+    // `group_by_stack`. Locked after the records.
+    stack_groups: Mutex<HashMap<u64, (usize, usize), DefaultHashBuilder, System>>,
+    symbols: RwLock<Symbols<NAME_LEN>>,
+    // What each frame address resolved to in `ResolveMode::Lazy`, shared by
+    // the records from the same sites. See `prune_symbol_cache`.
+    symbol_cache: Mutex<HashMap<usize, Vec<Call<NAME_LEN>, System>, DefaultHashBuilder, System>>,
+    // `start..end` address ranges whose frames aren't captured.
+    excluded_ranges: RwLock<Vec<(usize, usize), System>>,
+    // Load address and path of the modules seen in `ResolveMode::ModuleOffset`.
+    modules: RwLock<Vec<(usize, HeaplessString<NAME_LEN>), System>>,
+    event_hook: RwLock<Option<EventHook>>,
+    // See `set_age_watchdog`, with the `seq` of the records it fired for.
+    age_watchdog: RwLock<Option<(Duration, AgeHook<STACK_SIZE, NAME_LEN>)>>,
+    aged_seqs: Mutex<HashSet<u64, DefaultHashBuilder, System>>,
+    // The last `event_log` events, oldest first.
+    event_log: Mutex<VecDeque<LoggedEvent, System>>,
+    logged_events: AtomicU64,
+    #[cfg(any(test, feature = "test-util"))]
+    backtrace_provider: RwLock<Option<BacktraceProvider<NAME_LEN>>>,
+    site_counts: Mutex<HashMap<u64, usize, DefaultHashBuilder, System>>,
+    // See `LeakTracerBuilder::max_profile_records`: `(ptr, seq)` of the
+    // profile's records oldest first, and every allocation by stack key.
+    profile_order: Mutex<VecDeque<(usize, u64), System>>,
+    profile_sites: Mutex<HashMap<u64, (usize, usize), DefaultHashBuilder, System>>,
+    // Fingerprints admitted under `max_call_sites`, and what the others allocated.
+    call_sites: Mutex<HashSet<u64, DefaultHashBuilder, System>>,
+    other_site_allocs: AtomicUsize,
+    other_site_bytes: AtomicUsize,
+    // Live allocations when records aren't stored, see `store_records`.
+    unrecorded_allocations: AtomicUsize,
+    clock: RwLock<fn() -> Duration>,
+    // Allocations counted since `rate_window_start` (in clock nanoseconds).
+    rate_window_start: AtomicU64,
+    rate_allocs: AtomicUsize,
+    rate_bytes: AtomicUsize,
+    // One in `sample_interval` small allocations is recorded, counted by
+    // `sample_tick`, see `adaptive_sampling`.
+    sample_interval: AtomicUsize,
+    sample_tick: AtomicUsize,
+    // See `LeakTracer::frame_skip`.
+    frame_skip: OnceLock<usize>,
+    resolver_running: AtomicBool,
+    // `(ptr, seq)` of the records waiting for the resolver thread.
+    resolve_queue: Mutex<Vec<(usize, u64), System>>,
+    // Records taken off the queue and not written back yet.
+    resolving: AtomicUsize,
+    realloc_grow_bytes: AtomicUsize,
+    realloc_shrink_bytes: AtomicUsize,
+    peak_live_allocations: AtomicUsize,
+    alloc_count: AtomicU64,
+    free_count: AtomicU64,
+    // Total lifetime in clock nanoseconds of the tracked blocks freed, and
+    // their number, see `avg_lifetime`.
+    lifetime_nanos: AtomicU64,
+    lifetimes: AtomicU64,
+    // See `on_double_free`.
+    freed: Mutex<double_free::FreedBlocks>,
+    double_frees: AtomicUsize,
+    // Stacks captured so far, counted against the capture budget.
+    captures: AtomicUsize,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Default
+    for LeakTracerInner<STACK_SIZE, NAME_LEN>
+{
+    fn default() -> Self {
+        Self::with_lock(LockKind::Spin)
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracerInner<STACK_SIZE, NAME_LEN> {
+    fn blocking() -> Self {
+        Self::with_lock(LockKind::Blocking)
+    }
+
+    fn with_lock(lock_kind: LockKind) -> Self {
+        Self {
+            allocates: RecordLock::new(lock_kind, HashMap::default()),
+            next_seq: AtomicU64::new(0),
+            live_bytes: AtomicUsize::new(0),
+            class_bytes: [const { AtomicUsize::new(0) }; SIZE_CLASSES],
+            dedup: RecordLock::new(lock_kind, Default::default()),
+            stack_groups: Mutex::new(HashMap::default()),
+            symbols: RwLock::new(Vec::new_in(System)),
+            symbol_cache: Mutex::new(HashMap::default()),
+            excluded_ranges: RwLock::new(Vec::new_in(System)),
+            modules: RwLock::new(Vec::new_in(System)),
+            event_hook: RwLock::new(None),
+            age_watchdog: RwLock::new(None),
+            aged_seqs: Mutex::new(HashSet::default()),
+            event_log: Mutex::new(VecDeque::new_in(System)),
+            logged_events: AtomicU64::new(0),
+            #[cfg(any(test, feature = "test-util"))]
+            backtrace_provider: RwLock::new(None),
+            site_counts: Mutex::new(HashMap::default()),
+            profile_order: Mutex::new(VecDeque::new_in(System)),
+            profile_sites: Mutex::new(HashMap::default()),
+            call_sites: Mutex::new(HashSet::default()),
+            other_site_allocs: AtomicUsize::new(0),
+            other_site_bytes: AtomicUsize::new(0),
+            unrecorded_allocations: AtomicUsize::new(0),
+            clock: RwLock::new(monotonic_now),
+            rate_window_start: AtomicU64::new(0),
+            rate_allocs: AtomicUsize::new(0),
+            rate_bytes: AtomicUsize::new(0),
+            sample_interval: AtomicUsize::new(1),
+            sample_tick: AtomicUsize::new(0),
+            frame_skip: OnceLock::new(),
+            resolver_running: AtomicBool::new(false),
+            resolve_queue: Mutex::new(Vec::new_in(System)),
+            resolving: AtomicUsize::new(0),
+            realloc_grow_bytes: AtomicUsize::new(0),
+            realloc_shrink_bytes: AtomicUsize::new(0),
+            peak_live_allocations: AtomicUsize::new(0),
+            alloc_count: AtomicU64::new(0),
+            free_count: AtomicU64::new(0),
+            lifetime_nanos: AtomicU64::new(0),
+            lifetimes: AtomicU64::new(0),
+            freed: Mutex::new(Default::default()),
+            double_frees: AtomicUsize::new(0),
+            captures: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// When the frames of an allocation's backtrace get symbolized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveMode {
+    /// While recording the allocation. The default.
+    Eager,
+    /// Only the frame addresses are stored, and symbolized by
+    /// [`LeakTracer::resolve`] (which [`LeakTracer::get_leaks`] calls).
+    /// Allocations get much cheaper, reports slower.
+    Lazy,
+    /// Each frame is stored as the module it's in and its offset there, see
+    /// [`Call::module`], and never symbolized. Nearly as cheap as `Lazy`, and
+    /// the stacks can be symbolized offline since they don't depend on where
+    /// the modules were loaded.
+    ModuleOffset,
+}
+
+/// What happens to an allocation whose backtrace came out empty, e.g. where
+/// unwinding isn't supported, see [`LeakTracerBuilder::on_capture_failure`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFailure {
+    /// Record it with an empty stack. The default.
+    Record,
+    /// Don't record it.
+    Skip,
+    /// Record it with a single [`UNWIND_FAILED`] frame, so it stands out
+    /// in reports.
+    Sentinel,
+}
+
+/// What happens to allocations past the
+/// [`LeakTracerBuilder::capture_budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Record them without a stack.
+    EmptyStack,
+    /// Don't record them.
+    Skip,
+}
+
+/// What happens when a tracked block is freed again, see
+/// [`LeakTracerBuilder::on_double_free`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoubleFree {
+    /// Don't look for double frees. The default.
+    Ignore,
+    /// Count them, see [`LeakTracer::double_frees`].
+    Record,
+    /// Count them and print the address to stderr.
+    Log,
+    /// Print the address to stderr and abort the process, like a sanitizer.
+    Abort,
+}
+
+/// Name of the frame recorded for [`CaptureFailure::Sentinel`].
+pub const UNWIND_FAILED: &str = "[unwind failed]";
+
+#[derive(Debug, Clone, Copy)]
+struct Options {
+    resolve_mode: ResolveMode,
+    enabled: bool,
+    capture_depth: usize,
+    lock_kind: LockKind,
+    on_capture_failure: CaptureFailure,
+    min_size: usize,
+    capture_budget: usize,
+    over_budget: OverBudget,
+    allocation_profile: bool,
+    max_profile_records: usize,
+    armed_threads_only: bool,
+    deduplicate: bool,
+    store_records: bool,
+    group_by_stack: bool,
+    names_only: bool,
+    flush_before_report: bool,
+    event_log: usize,
+    attribution_root: Option<&'static str>,
+    last_realloc_stack: bool,
+    on_double_free: DoubleFree,
+    // See `adaptive_sampling`, off with `sample_below == 0`.
+    sample_below: usize,
+    sample_max_rate: usize,
+}
+
+/// Configures a [`LeakTracer`]. Every method is `const`, so the result can
+/// initialize a `#[global_allocator]` static.
+pub struct LeakTracerBuilder<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    options: Options,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Default
+    for LeakTracerBuilder<STACK_SIZE, NAME_LEN>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracerBuilder<STACK_SIZE, NAME_LEN> {
+    pub const fn new() -> Self {
+        Self {
+            options: Options {
+                resolve_mode: ResolveMode::Eager,
+                enabled: true,
+                capture_depth: STACK_SIZE,
+                lock_kind: LockKind::Spin,
+                on_capture_failure: CaptureFailure::Record,
+                min_size: 0,
+                capture_budget: usize::MAX,
+                over_budget: OverBudget::EmptyStack,
+                allocation_profile: false,
+                max_profile_records: usize::MAX,
+                armed_threads_only: false,
+                deduplicate: false,
+                store_records: true,
+                group_by_stack: false,
+                names_only: false,
+                flush_before_report: false,
+                event_log: 0,
+                attribution_root: None,
+                last_realloc_stack: false,
+                on_double_free: DoubleFree::Ignore,
+                sample_below: 0,
+                sample_max_rate: usize::MAX,
+            },
+        }
+    }
+
+    pub const fn resolve_mode(mut self, mode: ResolveMode) -> Self {
+        self.options.resolve_mode = mode;
+        self
+    }
+
+    /// Whether the tracer starts enabled, the default. Startup allocations
+    /// are usually noise, a tracer built with `enabled(false)` can be armed
+    /// later with [`LeakTracer::arm`].
+    pub const fn enabled(mut self, enabled: bool) -> Self {
+        self.options.enabled = enabled;
+        self
+    }
+
+    /// How many frames are captured per allocation, at most `STACK_SIZE`
+    /// (the default). Shallower stacks are quicker to capture.
+    pub const fn capture_depth(mut self, depth: usize) -> Self {
+        self.options.capture_depth = if depth < STACK_SIZE {
+            depth
+        } else {
+            STACK_SIZE
+        };
+        self
+    }
+
+    /// Only record allocations of at least `size` bytes. Reallocations of
+    /// recorded blocks stay recorded whatever their new size.
+    pub const fn min_size(mut self, size: usize) -> Self {
+        self.options.min_size = size;
+        self
+    }
+
+    /// Only capture the stacks of the first `budget` allocations, to bound the
+    /// cost while still seeing how the program starts. Later ones are handled
+    /// as `over_budget` says.
+    pub const fn capture_budget(mut self, budget: usize, over_budget: OverBudget) -> Self {
+        self.options.capture_budget = budget;
+        self.options.over_budget = over_budget;
+        self
+    }
+
+    /// Profile allocations rather than look for leaks: frees are counted but
+    /// leave the records in place, so every allocation site stays visible
+    /// with its full stack. Unless an
+    /// [event hook](LeakTracer::set_event_hook) is set, frees don't even
+    /// take the record lock. A record only goes away when its address is
+    /// handed out again, or it's pushed out by
+    /// [`max_profile_records`](Self::max_profile_records). Every allocation
+    /// is also counted by site, see [`LeakTracer::profile_sites`].
+    pub const fn allocation_profile(mut self, enabled: bool) -> Self {
+        self.options.allocation_profile = enabled;
+        self
+    }
+
+    /// With [`allocation_profile`](Self::allocation_profile), keeps at most
+    /// the `max` latest records, dropping the oldest. Unbounded by default.
+    pub const fn max_profile_records(mut self, max: usize) -> Self {
+        self.options.max_profile_records = max;
+        self
+    }
+
+    /// Only record allocations made by threads that opted in with
+    /// [`LeakTracer::arm_current_thread`], to look at one suspect worker.
+    /// Frees are followed on every thread.
+    pub const fn armed_threads_only(mut self, enabled: bool) -> Self {
+        self.options.armed_threads_only = enabled;
+        self
+    }
+
+    /// Keep a single record, with a [`AllocationRecord::multiplicity`], for
+    /// the live allocations of the same size made from the same place, e.g.
+    /// the entries of a cache. That saves a record per allocation, but the
+    /// merged record's address, thread, label and time are those of one of
+    /// them only. Reports and the other aggregations still count every
+    /// allocation; only the records returned count once.
+    pub const fn deduplicate(mut self, enabled: bool) -> Self {
+        self.options.deduplicate = enabled;
+        self
+    }
+
+    /// Whether to keep a record of each allocation, true by default. Without
+    /// them nothing is captured and only the counters and live totals (see
+    /// [`LeakTracer::stats`] and [`LeakTracer::live_bytes`]) are kept, which
+    /// is much cheaper. `live_usable_bytes` is then the requested size, and
+    /// blocks allocated while tracking was off shouldn't be freed while on,
+    /// since the live totals can't tell them apart.
+    pub const fn store_records(mut self, store: bool) -> Self {
+        self.options.store_records = store;
+        self
+    }
+
+    /// Whether to keep count and bytes of the live allocations per
+    /// [`AllocationRecord::stack_key`] as they come and go, so
+    /// [`LeakTracer::leaks_grouped_by_stack`] reads them instead of going over
+    /// every record. Costs a second map update per allocation and free.
+    pub const fn group_by_stack(mut self, enabled: bool) -> Self {
+        self.options.group_by_stack = enabled;
+        self
+    }
+
+    /// Whether to keep only the function names of captured frames, leaving
+    /// [`Call::filename`], `line` and `col` empty. The strings are stored
+    /// inline, so this saves copying the paths rather than memory; pick a
+    /// smaller `NAME_LEN` too to shrink the records.
+    pub const fn names_only(mut self, enabled: bool) -> Self {
+        self.options.names_only = enabled;
+        self
+    }
+
+    /// Whether [`LeakTracer::report`] and [`LeakTracer::get_leaks`] call
+    /// [`LeakTracer::flush_pending`] first, waiting for the resolver thread
+    /// to drain its queue rather than resolving what's left alongside it.
+    /// Only matters with [`LeakTracer::start_resolver`].
+    pub const fn flush_before_report(mut self, enabled: bool) -> Self {
+        self.options.flush_before_report = enabled;
+        self
+    }
+
+    /// Keeps the last `capacity` tracked events (allocations, frees and
+    /// reallocations, without stacks) for [`LeakTracer::recent_events`].
+    /// Off with 0, the default.
+    pub const fn event_log(mut self, capacity: usize) -> Self {
+        self.options.event_log = capacity;
+        self
+    }
+
+    /// Attributes each allocation to its first frame whose symbol name or
+    /// filename contains `root`, e.g. the name of your crate, rather than to
+    /// the std helper that allocated for it. [`LeakTracer::report`] then
+    /// groups by that frame and the summary lines show it; the full stack is
+    /// still recorded. Allocations without such a frame are grouped as before.
+    pub const fn attribution_root(mut self, root: &'static str) -> Self {
+        self.options.attribution_root = Some(root);
+        self
+    }
+
+    /// Also capture the stack of each reallocation that moves a tracked
+    /// block, as [`AllocationRecord::last_realloc_stack`], which verbose
+    /// reports print after the original stack. Each move then costs a
+    /// resolved capture, whatever the resolve mode.
+    pub const fn last_realloc_stack(mut self, enabled: bool) -> Self {
+        self.options.last_realloc_stack = enabled;
+        self
+    }
+
+    /// Whether to catch frees of tracked blocks already freed, among the
+    /// last [`DOUBLE_FREE_WINDOW`] ones, and what to do then. Caught frees
+    /// aren't passed on to `System`. Needs the records, so it does nothing
+    /// with [`store_records(false)`](Self::store_records) or
+    /// [`allocation_profile`](Self::allocation_profile).
+    pub const fn on_double_free(mut self, action: DoubleFree) -> Self {
+        self.options.on_double_free = action;
+        self
+    }
+
+    /// Records only some of the allocations smaller than `size` while more
+    /// than `max_rate` allocations a second are made (counted over each
+    /// [`RATE_WINDOW`]), so bursts of small ones don't slow the program down
+    /// as much. The interval between recorded ones follows the rate, see
+    /// [`LeakTracer::sample_interval`], and goes back down once the rate
+    /// falls under half of `max_rate`. Allocations left out aren't counted
+    /// in the live totals, like those under [`min_size`](Self::min_size).
+    pub const fn adaptive_sampling(mut self, size: usize, max_rate: usize) -> Self {
+        self.options.sample_below = size;
+        // A rate of 0 would never sample anything in.
+        self.options.sample_max_rate = if max_rate == 0 { 1 } else { max_rate };
+        self
+    }
+
+    /// What to do with allocations no frame could be captured for.
+    pub const fn on_capture_failure(mut self, policy: CaptureFailure) -> Self {
+        self.options.on_capture_failure = policy;
+        self
+    }
+
+    /// The lock guarding the records, [`LockKind::Spin`] by default.
+    pub const fn lock_kind(mut self, kind: LockKind) -> Self {
+        self.options.lock_kind = kind;
+        self
+    }
+
+    pub const fn build(self) -> LeakTracer<STACK_SIZE, NAME_LEN> {
+        LeakTracer {
+            inner: Lazy::new(match self.options.lock_kind {
+                LockKind::Spin => LeakTracerInner::default,
+                LockKind::Blocking => LeakTracerInner::blocking,
+            }),
+            enabled: AtomicBool::new(self.options.enabled),
+            disable_depth: AtomicUsize::new(0),
+            capture_depth: AtomicUsize::new(self.options.capture_depth),
+            max_call_sites: AtomicUsize::new(usize::MAX),
+            report_requested: AtomicBool::new(false),
+            detached: AtomicBool::new(false),
+            options: self.options,
+        }
+    }
+}
+
+pub struct LeakTracer<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    inner: Lazy<LeakTracerInner<STACK_SIZE, NAME_LEN>>,
+    enabled: AtomicBool,
+    /// Number of live [`DisableGuard`]s, tracking is paused while non-zero.
+    disable_depth: AtomicUsize,
+    capture_depth: AtomicUsize,
+    max_call_sites: AtomicUsize,
+    // Set by `request_report`, outside of `inner` so that doesn't get
+    // initialized in a signal handler.
+    report_requested: AtomicBool,
+    // Set by `detach`, checked before anything else.
+    detached: AtomicBool,
+    options: Options,
+}
+
+pub type LeakTracerDefault = LeakTracer<10>;
+
+struct LeaksDisplay<'a, const STACK_SIZE: usize, const NAME_LEN: usize> {
+    tracer: &'a LeakTracer<STACK_SIZE, NAME_LEN>,
+    options: ReportOptions,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Display
+    for LeaksDisplay<'_, STACK_SIZE, NAME_LEN>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let _guard = ReentrancyGuard::enter();
+        let allocates = self.tracer.inner.allocates.lock();
+        if self.options.strip_addresses {
+            // The map's order follows the addresses.
+            let mut records: Vec<_> = allocates.values().collect();
+            records.sort_by_key(|record| record.seq);
+            for record in records {
+                write!(f, "{}", record.display_with(self.options))?;
+            }
+            return Ok(());
+        }
+        for record in allocates.values() {
+            write!(f, "{}", record.display_with(self.options))?;
+        }
+        Ok(())
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Default for LeakTracer<STACK_SIZE, NAME_LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    pub const fn new() -> Self {
+        LeakTracerBuilder::new().build()
+    }
+
+    pub const fn builder() -> LeakTracerBuilder<STACK_SIZE, NAME_LEN> {
+        LeakTracerBuilder::new()
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Enables or disables tracking, returning whether it was enabled, so the
+    /// previous state can be restored later.
+    pub fn set_enabled(&self, enabled: bool) -> bool {
+        self.enabled.swap(enabled, Ordering::SeqCst)
+    }
+
+    /// Stops tracking for good: from now on the tracer only forwards to
+    /// `System`, without even looking at its records, which stay as they were
+    /// and can still be queried. Unlike [`disable`](Self::disable) this can't
+    /// be undone.
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::SeqCst);
+    }
+
+    /// Makes this tracer the one returned by [`LeakTracer::global`], usually
+    /// the `#[global_allocator]` static. Only the first registration sticks,
+    /// returns whether this one did. [`leak_tracer!`] registers its tracer.
+    pub fn register_global(&'static self) -> bool {
+        let entry = (TypeId::of::<Self>(), self as *const Self as usize);
+        *GLOBAL.get_or_init(|| entry) == entry
+    }
+
+    /// The registered tracer, if there is one and it's of this type.
+    pub fn global() -> Option<&'static Self> {
+        match GLOBAL.get() {
+            Some(&(type_id, addr)) if type_id == TypeId::of::<Self>() => {
+                Some(unsafe { &*(addr as *const Self) })
+            }
+            _ => None,
+        }
+    }
+
+    /// Changes how many frames are captured for the next allocations, see
+    /// [`LeakTracerBuilder::capture_depth`].
+    pub fn set_capture_depth(&self, depth: usize) {
+        self.capture_depth
+            .store(depth.min(STACK_SIZE), Ordering::Relaxed);
+    }
+
+    /// Only records allocations from the first `max` distinct call sites (by
+    /// [`AllocationRecord::fingerprint`]), to bound memory in pathological
+    /// code, e.g. with [`LeakTracerBuilder::allocation_profile`]. Those from
+    /// later sites are only counted, see [`LeakTracer::other_call_sites`].
+    /// Sites stay admitted once seen, even after their blocks are freed.
+    pub fn set_max_call_sites(&self, max: usize) {
+        self.max_call_sites.store(max, Ordering::Relaxed);
+    }
+
+    /// `(count, bytes)` of the allocations left unrecorded since their call
+    /// site was over [`LeakTracer::set_max_call_sites`].
+    pub fn other_call_sites(&self) -> (usize, usize) {
+        (
+            self.inner.other_site_allocs.load(Ordering::Relaxed),
+            self.inner.other_site_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    fn admit_call_site(&self, record: &AllocationRecord<STACK_SIZE, NAME_LEN>) -> bool {
+        let max = self.max_call_sites.load(Ordering::Relaxed);
+        if max == usize::MAX {
+            return true;
+        }
+        let fingerprint = record.fingerprint();
+        let mut sites = self.inner.call_sites.lock();
+        if sites.contains(&fingerprint) {
+            return true;
+        }
+        if sites.len() < max {
+            sites.insert(fingerprint);
+            return true;
+        }
+        self.inner.other_site_allocs.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .other_site_bytes
+            .fetch_add(record.size, Ordering::Relaxed);
+        false
+    }
+
+    /// Pauses tracking, on every thread, until the returned guard is dropped.
+    /// Scopes nest, and don't touch the [`LeakTracer::enable`] flag.
+    pub fn disable_scope(&self) -> DisableGuard<'_> {
+        self.disable_depth.fetch_add(1, Ordering::SeqCst);
+        DisableGuard {
+            depth: &self.disable_depth,
+        }
+    }
+
+    /// Runs `f` inside a [`disable_scope`](Self::disable_scope), so nothing
+    /// it allocates is recorded. Tracking resumes even if `f` panics.
+    pub fn with_tracking_disabled<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _scope = self.disable_scope();
+        f()
+    }
+
+    /// Whether an allocation of `layout` made now on this thread would be
+    /// recorded, given every filter but sampling. Meant for finding why
+    /// something is missing from a report. Once
+    /// [`set_max_call_sites`](Self::set_max_call_sites) is reached this is
+    /// false, though sites admitted before still get recorded.
+    pub fn would_track(&self, layout: &Layout) -> bool {
+        let max_sites = self.max_call_sites.load(Ordering::Relaxed);
+        !self.detached.load(Ordering::Relaxed)
+            && self.tracking()
+            && self.options.store_records
+            && self.admits(layout.size())
+            && !self.skips_over_budget(self.inner.captures.load(Ordering::Relaxed))
+            && (max_sites == usize::MAX || self.inner.call_sites.lock().len() < max_sites)
+    }
+
+    /// The filters an allocation of `size` goes through before its stack is
+    /// captured.
+    fn admits(&self, size: usize) -> bool {
+        size >= self.options.min_size && self.records_this_thread()
+    }
+
+    /// Whether the allocation made after `captures` stack captures is left
+    /// out, see [`LeakTracerBuilder::capture_budget`].
+    fn skips_over_budget(&self, captures: usize) -> bool {
+        captures >= self.options.capture_budget && self.options.over_budget == OverBudget::Skip
+    }
+
+    /// Opts the calling thread into tracking until the returned guard is
+    /// dropped, see [`LeakTracerBuilder::armed_threads_only`].
+    pub fn arm_current_thread(&self) -> ThreadArmGuard {
+        ThreadArmGuard {
+            was_armed: ARMED.try_with(|armed| armed.replace(true)).unwrap_or(false),
+            _not_send: PhantomData,
+        }
+    }
+
+    fn records_this_thread(&self) -> bool {
+        !self.options.armed_threads_only || ARMED.try_with(Cell::get).unwrap_or(false)
+    }
+
+    fn tracking(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst) && self.disable_depth.load(Ordering::SeqCst) == 0
+    }
+
+    /// Paths of the modules [`Call::module`] refers to, in
+    /// [`ResolveMode::ModuleOffset`].
+    pub fn modules(&self) -> Vec<String> {
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .modules
+            .read()
+            .iter()
+            .map(|(_, path)| path.to_string())
+            .collect()
+    }
+
+    /// Turns `frames` into calls pointing into their module.
+    fn module_offsets(&self, frames: &[usize]) -> HeaplessVec<Call<NAME_LEN>, STACK_SIZE> {
+        let mut stack = HeaplessVec::new();
+        for &ip in frames {
+            let Some(info) = module::lookup(ip) else {
+                let _ = stack.push(Call {
+                    name: None,
+                    filename: None,
+                    line: None,
+                    col: None,
+                    addr: 0,
+                    ip,
+                    module: None,
+                    offset: 0,
+                });
+                continue;
+            };
+            let known = self
+                .inner
+                .modules
+                .read()
+                .iter()
+                .position(|(base, _)| *base == info.base);
+            let module = known.unwrap_or_else(|| {
+                let mut modules = self.inner.modules.write();
+                // Another thread may have added it meanwhile.
+                modules
+                    .iter()
+                    .position(|(base, _)| *base == info.base)
+                    .unwrap_or_else(|| {
+                        let path = info
+                            .path
+                            .map(|path| path.to_string_lossy())
+                            .unwrap_or_default();
+                        modules.push((info.base, truncated_path(path.chars())));
+                        modules.len() - 1
+                    })
+            });
+            let _ = stack.push(Call {
+                name: None,
+                filename: None,
+                line: None,
+                col: None,
+                addr: 0,
+                ip,
+                module: Some(module),
+                offset: ip - info.base,
+            });
+        }
+        stack
+    }
+
+    /// Leaves the frames whose return address is in `start..end` out of the
+    /// captured stacks, e.g. the loaded range of a whole allocator library.
+    /// Cheaper than matching symbol names, and only affects allocations made
+    /// afterwards.
+    pub fn add_excluded_range(&self, start: usize, end: usize) {
+        let _guard = ReentrancyGuard::enter();
+        self.inner.excluded_ranges.write().push((start, end));
+    }
+
+    /// Names the code starting at `addr`, for frames symbolization can't name,
+    /// e.g. from a symbol map of a stripped binary. A frame gets the name
+    /// registered at the closest address at or below its own. Only records
+    /// captured or resolved afterwards are affected.
+    pub fn register_symbol(&self, addr: usize, name: &str) {
+        let name = truncated(name);
+        let _guard = ReentrancyGuard::enter();
+        let mut symbols = self.inner.symbols.write();
+        match symbols.binary_search_by_key(&addr, |(addr, _)| *addr) {
+            Ok(idx) => symbols[idx].1 = name,
+            Err(idx) => symbols.insert(idx, (addr, name)),
+        }
+        // Frames cached without a name may have one now.
+        self.inner.symbol_cache.lock().clear();
+    }
+
+    /// Labels the allocations made by this thread until the returned guard is
+    /// dropped, e.g. with the name of the operation being run. Labels nest,
+    /// records get the innermost one.
+    pub fn push_label(&self, label: &str) -> LabelGuard {
+        let pushed = LABELS
+            .try_with(|labels| labels.borrow_mut().push(truncated(label)).is_ok())
+            .unwrap_or(false);
+        LabelGuard {
+            pushed,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Forgets every record and enables tracking, so only what's allocated from
+    /// now on is reported.
+    pub fn arm(&self) {
+        self.clear();
+        self.enable();
+    }
+
+    /// Forgets every record, as if all tracked allocations had been freed.
+    pub fn clear(&self) {
+        let mut allocates = self.inner.allocates.lock();
+        allocates.clear();
+        self.inner.dedup.lock().clear();
+        self.inner.stack_groups.lock().clear();
+        self.reset_live_bytes();
+    }
+
+    /// Zeroes the cumulative counters, keeping the live records.
+    pub fn reset_stats(&self) {
+        self.inner.realloc_grow_bytes.store(0, Ordering::Relaxed);
+        self.inner.realloc_shrink_bytes.store(0, Ordering::Relaxed);
+        self.inner.alloc_count.store(0, Ordering::Relaxed);
+        self.inner.free_count.store(0, Ordering::Relaxed);
+        self.reset_other_counters();
+        self.reset_peak_live_allocations();
+    }
+
+    /// Zeroes the cumulative counters that aren't part of [`Stats`].
+    fn reset_other_counters(&self) {
+        let now = self.now().as_nanos() as u64;
+        self.inner.rate_window_start.store(now, Ordering::Relaxed);
+        self.inner.rate_allocs.store(0, Ordering::Relaxed);
+        self.inner.rate_bytes.store(0, Ordering::Relaxed);
+        self.inner.lifetime_nanos.store(0, Ordering::Relaxed);
+        self.inner.lifetimes.store(0, Ordering::Relaxed);
+        self.inner.other_site_allocs.store(0, Ordering::Relaxed);
+        self.inner.other_site_bytes.store(0, Ordering::Relaxed);
+        self.inner.profile_sites.lock().clear();
+    }
+
+    /// Mean time between allocation and free of the tracked blocks freed
+    /// since the tracer was made or [`reset_stats`](Self::reset_stats), by
+    /// the tracer's clock. `None` until one is freed. Long-lived leaks don't
+    /// take part, compare with [`LeakTracer::leaks_by_score`].
+    pub fn avg_lifetime(&self) -> Option<Duration> {
+        let count = self.inner.lifetimes.load(Ordering::Relaxed);
+        let total = self.inner.lifetime_nanos.load(Ordering::Relaxed);
+        (count > 0).then(|| Duration::from_nanos(total / count))
+    }
+
+    /// Number of `alloc` calls seen while tracking.
+    pub fn alloc_count(&self) -> u64 {
+        self.inner.alloc_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of `dealloc` calls seen while tracking, whether or not the
+    /// block was tracked.
+    pub fn free_count(&self) -> u64 {
+        self.inner.free_count.load(Ordering::Relaxed)
+    }
+
+    /// The most allocations that were live at once.
+    pub fn peak_live_allocations(&self) -> usize {
+        self.inner.peak_live_allocations.load(Ordering::Relaxed)
+    }
+
+    /// Starts tracking the peak over from the current number of allocations.
+    pub fn reset_peak_live_allocations(&self) {
+        let allocates = self.inner.allocates.lock();
+        let merged = self.inner.dedup.lock().merged();
+        self.inner
+            .peak_live_allocations
+            .store(allocates.len() + merged, Ordering::Relaxed);
+    }
+
+    /// Replaces the monotonic clock used for time based statistics, e.g. to
+    /// drive them from a simulated clock in tests.
+    pub fn set_clock(&self, clock: fn() -> Duration) {
+        *self.inner.clock.write() = clock;
+    }
+
+    fn now(&self) -> Duration {
+        (self.inner.clock.read())()
+    }
+
+    /// Tracked allocations per second and bytes allocated per second, over
+    /// the current [`RATE_WINDOW`]. A window starts over on the first
+    /// allocation after it's elapsed.
+    pub fn alloc_rate(&self) -> (f64, f64) {
+        let start = Duration::from_nanos(self.inner.rate_window_start.load(Ordering::Relaxed));
+        let elapsed = self.now().saturating_sub(start).as_secs_f64();
+        if elapsed == 0.0 {
+            return (0.0, 0.0);
+        }
+        let allocs = self.inner.rate_allocs.load(Ordering::Relaxed) as f64;
+        let bytes = self.inner.rate_bytes.load(Ordering::Relaxed) as f64;
+        (allocs / elapsed, bytes / elapsed)
+    }
+
+    fn count_rate(&self, size: usize) {
+        let now = self.now().as_nanos() as u64;
+        let start = self.inner.rate_window_start.load(Ordering::Relaxed);
+        if now.saturating_sub(start) >= RATE_WINDOW.as_nanos() as u64
+            && self
+                .inner
+                .rate_window_start
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let allocs = self.inner.rate_allocs.swap(0, Ordering::Relaxed);
+            self.inner.rate_bytes.store(0, Ordering::Relaxed);
+            if self.sampling() && allocs < self.options.sample_max_rate / 2 {
+                // Quiet again, come back down a step per window.
+                let _ = self.inner.sample_interval.fetch_update(
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                    |interval| Some((interval / 2).max(1)),
+                );
+            }
+        }
+        let allocs = self.inner.rate_allocs.fetch_add(1, Ordering::Relaxed) + 1;
+        self.inner.rate_bytes.fetch_add(size, Ordering::Relaxed);
+        if self.sampling() && allocs.is_multiple_of(self.options.sample_max_rate) {
+            // Record about `sample_max_rate` of them over the window so far.
+            self.inner
+                .sample_interval
+                .fetch_max(allocs / self.options.sample_max_rate + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn sampling(&self) -> bool {
+        self.options.sample_below > 0
+    }
+
+    /// Whether an allocation of `size` is left out by
+    /// [`LeakTracerBuilder::adaptive_sampling`].
+    fn sampled_out(&self, size: usize) -> bool {
+        if size >= self.options.sample_below {
+            return false;
+        }
+        let interval = self.inner.sample_interval.load(Ordering::Relaxed);
+        interval > 1
+            && !self
+                .inner
+                .sample_tick
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(interval)
+    }
+
+    /// One in how many allocations under the
+    /// [`adaptive_sampling`](LeakTracerBuilder::adaptive_sampling) size get
+    /// recorded right now, 1 while the rate is low or sampling is off.
+    pub fn sample_interval(&self) -> usize {
+        self.inner.sample_interval.load(Ordering::Relaxed)
+    }
+
+    /// Calls `hook` for every allocation that gets recorded (or counted,
+    /// without [`store_records`](LeakTracerBuilder::store_records)), and for
+    /// the reallocation and free of those, so every `Alloc` is matched by one
+    /// `Free`. Allocations left out by filters or sampling fire nothing.
+    ///
+    /// The hook runs outside the record lock and may set or clear the hook;
+    /// allocations made inside it are not tracked. Replaces any previously
+    /// set hook.
+    pub fn set_event_hook(&self, hook: impl Fn(AllocEvent) + Send + Sync + 'static) {
+        let hook: EventHook = Arc::new_in(hook, System);
+        *self.inner.event_hook.write() = Some(hook);
+    }
+
+    pub fn clear_event_hook(&self) {
+        self.inner.event_hook.write().take();
+    }
+
+    /// The events kept by [`LeakTracerBuilder::event_log`], oldest first.
+    pub fn recent_events(&self) -> Vec<LoggedEvent> {
+        let _guard = ReentrancyGuard::enter();
+        self.inner.event_log.lock().iter().copied().collect()
+    }
+
+    /// Records the calls returned by `provider` instead of unwinding, so
+    /// tests see the same stacks whatever the optimizations and inlining.
+    /// The calls are taken as resolved, up to the capture depth.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_backtrace_provider(
+        &self,
+        provider: impl Fn() -> Vec<Call<NAME_LEN>> + Send + Sync + 'static,
+    ) {
+        let provider: BacktraceProvider<NAME_LEN> = Box::new_in(provider, System);
+        *self.inner.backtrace_provider.write() = Some(provider);
+    }
+
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn clear_backtrace_provider(&self) {
+        self.inner.backtrace_provider.write().take();
+    }
+
+    /// Fills `stack` and `frames` from the backtrace provider, if one is set.
+    #[cfg(any(test, feature = "test-util"))]
+    fn provide_backtrace(
+        &self,
+        depth: usize,
+        stack: &mut HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+        frames: &mut HeaplessVec<usize, STACK_SIZE>,
+    ) -> bool {
+        let Some(provider) = self.inner.backtrace_provider.read().as_ref().map(|p| p()) else {
+            return false;
+        };
+        for call in provider.into_iter().take(depth) {
+            let _ = frames.push(call.ip);
+            let _ = stack.push(call);
+        }
+        true
+    }
+
+    #[cfg(not(any(test, feature = "test-util")))]
+    fn provide_backtrace(
+        &self,
+        _depth: usize,
+        _stack: &mut HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+        _frames: &mut HeaplessVec<usize, STACK_SIZE>,
+    ) -> bool {
+        false
+    }
+
+    pub fn get_leaks(
+        &self,
+    ) -> HashMap<usize, AllocationRecord<STACK_SIZE, NAME_LEN>, DefaultHashBuilder, System> {
+        let mut out = HashMap::default();
+        self.get_leaks_into(&mut out);
+        out
+    }
+
+    /// Like [`LeakTracer::get_leaks`], clearing and filling `out` instead,
+    /// so polling with the same map doesn't allocate once it is big enough.
+    pub fn get_leaks_into(
+        &self,
+        out: &mut HashMap<
+            usize,
+            AllocationRecord<STACK_SIZE, NAME_LEN>,
+            DefaultHashBuilder,
+            System,
+        >,
+    ) {
+        if self.options.flush_before_report {
+            self.flush_pending();
+        }
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        // The map allocates from `System`, the guard only keeps anything else
+        // done here from being recorded. Other threads keep being tracked.
+        let _guard = ReentrancyGuard::enter();
+        out.clear();
+        for (k, v) in self.inner.allocates.lock().iter() {
+            out.insert(*k, v.clone());
+        }
+    }
+
+    /// Borrows the live records without copying them, for `for` loops and
+    /// iterator adapters.
+    ///
+    /// The records stay locked until the guard is dropped: allocations made
+    /// meanwhile on other threads wait for it, and those made on this thread
+    /// (or frees of tracked memory) go unrecorded. Keep the loop short and
+    /// avoid allocating in it.
+    pub fn leaks_iter(&self) -> LeaksGuard<'_, STACK_SIZE, NAME_LEN> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let reentrancy = ReentrancyGuard::enter();
+        LeaksGuard {
+            records: self.inner.allocates.lock(),
+            _reentrancy: reentrancy,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Like [`LeakTracer::get_leaks`], but ordered by when the allocations
+    /// were recorded (then by address), so reports are reproducible.
+    pub fn get_leaks_sorted(&self) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        let mut out: Vec<_> = self.inner.allocates.lock().values().cloned().collect();
+        out.sort_by_key(|record| (record.seq, record.ptr));
+        out
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.live_stats();
+        self.read_counters(&mut stats);
+        stats
+    }
+
+    /// Copies the records and the stats at once, so they agree with each
+    /// other, e.g. for tools analysing the tracer from the outside.
+    pub fn snapshot_state(&self) -> TracerSnapshot<STACK_SIZE, NAME_LEN> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        let allocates = self.inner.allocates.lock();
+        let records = allocates.clone();
+        // The records can't change until the lock is released, read the
+        // counters before that too.
+        let mut stats = stats_of(records.values());
+        self.read_counters(&mut stats);
+        drop(allocates);
+        TracerSnapshot { records, stats }
+    }
+
+    fn read_counters(&self, stats: &mut Stats) {
+        stats.alloc_count = self.inner.alloc_count.load(Ordering::Relaxed);
+        stats.free_count = self.inner.free_count.load(Ordering::Relaxed);
+        stats.realloc_grow_bytes = self.inner.realloc_grow_bytes.load(Ordering::Relaxed);
+        stats.realloc_shrink_bytes = self.inner.realloc_shrink_bytes.load(Ordering::Relaxed);
+    }
+
+    /// Like [`LeakTracer::stats`], but also zeroes every counter
+    /// [`reset_stats`](Self::reset_stats) does, so what is read afterwards,
+    /// e.g. [`avg_lifetime`](Self::avg_lifetime), covers the new interval
+    /// only. The counters in [`Stats`] are swapped out one by one, so none
+    /// of their events is lost or counted twice between two drains.
+    pub fn drain_stats(&self) -> Stats {
+        let _guard = ReentrancyGuard::enter();
+        let mut stats = self.live_stats();
+        stats.alloc_count = self.inner.alloc_count.swap(0, Ordering::Relaxed);
+        stats.free_count = self.inner.free_count.swap(0, Ordering::Relaxed);
+        stats.realloc_grow_bytes = self.inner.realloc_grow_bytes.swap(0, Ordering::Relaxed);
+        stats.realloc_shrink_bytes = self.inner.realloc_shrink_bytes.swap(0, Ordering::Relaxed);
+        self.reset_other_counters();
+        self.reset_peak_live_allocations();
+        stats
+    }
+
+    /// [`drain_leaks`](Self::drain_leaks) and
+    /// [`drain_stats`](Self::drain_stats) at once, for interval reports: the
+    /// records are taken and the counters zeroed with the record lock held,
+    /// so every allocation lands in exactly one interval's records, and
+    /// every event in one interval's counters. An allocation counted just
+    /// before the drain may still have its record in the next interval.
+    pub fn reset_and_snapshot(&self) -> TracerSnapshot<STACK_SIZE, NAME_LEN> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        let mut allocates = self.inner.allocates.lock();
+        let records = std::mem::take(&mut *allocates);
+        self.inner.dedup.lock().clear();
+        self.inner.stack_groups.lock().clear();
+        self.reset_live_bytes();
+        let mut stats = stats_of(records.values());
+        stats.alloc_count = self.inner.alloc_count.swap(0, Ordering::Relaxed);
+        stats.free_count = self.inner.free_count.swap(0, Ordering::Relaxed);
+        stats.realloc_grow_bytes = self.inner.realloc_grow_bytes.swap(0, Ordering::Relaxed);
+        stats.realloc_shrink_bytes = self.inner.realloc_shrink_bytes.swap(0, Ordering::Relaxed);
+        self.reset_other_counters();
+        // Nothing is live anymore.
+        self.inner.peak_live_allocations.store(0, Ordering::Relaxed);
+        drop(allocates);
+        TracerSnapshot { records, stats }
+    }
+
+    fn live_stats(&self) -> Stats {
+        if !self.options.store_records {
+            let live_bytes = self.live_bytes();
+            return Stats {
+                live_allocations: self.inner.unrecorded_allocations.load(Ordering::SeqCst),
+                live_bytes,
+                live_usable_bytes: live_bytes,
+                ..Stats::default()
+            };
+        }
+        stats_of(self.inner.allocates.lock().values())
+    }
+
+    /// Formats every live allocation without copying any of them.
+    ///
+    /// The record lock is held while formatting, and allocations made by the
+    /// formatter on this thread are not tracked. Other threads allocating
+    /// meanwhile wait for it, so keep the output target cheap.
+    pub fn display_leaks(&self) -> impl Display + '_ {
+        self.display_leaks_with(ReportOptions::new())
+    }
+
+    /// Like [`LeakTracer::display_leaks`], with the given options.
+    pub fn display_leaks_with(&self, options: ReportOptions) -> impl Display + '_ {
+        LeaksDisplay {
+            tracer: self,
+            options,
+        }
+    }
+
+    /// Counts the live allocations by size range, as `(label, count, bytes)`
+    /// from the smallest range up. Empty ranges are included.
+    pub fn count_by_size_bucket(&self) -> Vec<(&'static str, usize, usize)> {
+        let _guard = ReentrancyGuard::enter();
+        let mut buckets = SIZE_BUCKETS.map(|(_, label)| (label, 0, 0));
+        for record in self.inner.allocates.lock().values() {
+            let idx = SIZE_BUCKETS
+                .iter()
+                .position(|(upper, _)| record.size <= *upper)
+                .unwrap_or(SIZE_BUCKETS.len() - 1);
+            buckets[idx].1 += record.multiplicity;
+            buckets[idx].2 += record.size * record.multiplicity;
+        }
+        buckets.to_vec()
+    }
+
+    /// Counts the live allocations by age, as `(label, count)` from the
+    /// youngest range up. Empty ranges are included.
+    pub fn age_histogram(&self) -> Vec<(&'static str, usize)> {
+        let _guard = ReentrancyGuard::enter();
+        let now = self.now();
+        let mut buckets = AGE_BUCKETS.map(|(_, label)| (label, 0));
+        for record in self.inner.allocates.lock().values() {
+            let age = now.saturating_sub(record.allocated_at);
+            let idx = AGE_BUCKETS
+                .iter()
+                .position(|(upper, _)| age < *upper)
+                .unwrap_or(AGE_BUCKETS.len() - 1);
+            buckets[idx].1 += record.multiplicity;
+        }
+        buckets.to_vec()
+    }
+
+    /// Marks the current point in the allocation sequence, to later get what
+    /// was allocated after it with [`LeakTracer::leaks_since`].
+    pub fn mark(&self) -> Marker {
+        Marker {
+            seq: self.inner.next_seq.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Returns the live allocations recorded after `marker` was taken.
+    pub fn leaks_since(&self, marker: &Marker) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| record.seq > marker.seq)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the live allocations most likely to be real leaks first: both
+    /// large and old. They're ranked by `size * age.powf(age_weight)`, with
+    /// the age in seconds by the tracer's clock, so `0.0` ranks by size only
+    /// and `1.0` by byte-seconds held.
+    pub fn leaks_by_score(&self, age_weight: f64) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let now = self.now();
+        let score = |record: &AllocationRecord<STACK_SIZE, NAME_LEN>| {
+            let age = now.saturating_sub(record.allocated_at).as_secs_f64();
+            (record.size * record.multiplicity) as f64 * age.powf(age_weight)
+        };
+        let _guard = ReentrancyGuard::enter();
+        let mut out: Vec<_> = self
+            .inner
+            .allocates
+            .lock()
+            .values()
+            .map(|record| (score(record), record.clone()))
+            .collect();
+        out.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        out.into_iter().map(|(_, record)| record).collect()
+    }
+
+    /// Bytes held by the live allocations recorded after `marker` was taken,
+    /// i.e. what was retained since, without copying the records.
+    pub fn bytes_allocated_since(&self, marker: &Marker) -> usize {
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| record.seq > marker.seq)
+            .map(|record| record.size * record.multiplicity)
+            .sum()
+    }
+
+    /// Returns the live allocations whose [`AllocationRecord::fingerprint`]
+    /// is not in `allow`, e.g. a checked-in list of known leaks, so an empty
+    /// result means nothing new leaked.
+    pub fn leaks_excluding_fingerprints(
+        &self,
+        allow: &[u64],
+    ) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| !allow.contains(&record.fingerprint()))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the live allocations last produced by `origin`.
+    pub fn leaks_with_origin(
+        &self,
+        origin: AllocOrigin,
+    ) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| record.origin == origin)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the live allocations of `min..=max` bytes, copying only those.
+    pub fn leaks_between_sizes(
+        &self,
+        min: usize,
+        max: usize,
+    ) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| (min..=max).contains(&record.size))
+            .cloned()
+            .collect()
+    }
+
+    /// The live record holding the most bytes, counting every allocation a
+    /// deduplicated record stands for, if any. Only that record is resolved
+    /// and copied.
+    pub fn largest_leak(&self) -> Option<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        let _guard = ReentrancyGuard::enter();
+        loop {
+            let (ptr, seq) = self
+                .inner
+                .allocates
+                .lock()
+                .values()
+                .max_by_key(|record| record.size * record.multiplicity)
+                .map(|record| (record.ptr, record.seq))?;
+            self.resolve_records(&[(ptr, seq)], &mut |_, _| {});
+            // Unless it was freed meanwhile, then look again.
+            let allocates = self.inner.allocates.lock();
+            if let Some(record) = allocates.get(&ptr).filter(|record| record.seq == seq) {
+                return Some(record.clone());
+            }
+        }
+    }
+
+    /// Symbolizes the records that were captured in [`ResolveMode::Lazy`].
+    /// Records that are already resolved are left alone.
+    pub fn resolve(&self) {
+        self.resolve_all(|_, _| {});
+    }
+
+    /// Like [`resolve`](Self::resolve), calling `progress(done, total)` every
+    /// [`RESOLVE_PROGRESS_STEP`] records and once at the end, to show how a
+    /// long resolution is going.
+    pub fn resolve_all(&self, mut progress: impl FnMut(usize, usize)) {
+        let _guard = ReentrancyGuard::enter();
+
+        let pending: Vec<(usize, u64)> = self
+            .inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| !record.symbolized)
+            .map(|record| (record.ptr, record.seq))
+            .collect();
+        self.resolve_records(&pending, &mut progress);
+    }
+
+    /// Symbolizes the given `(ptr, seq)` records, skipping those that were
+    /// freed (and maybe reused) or resolved since.
+    fn resolve_records(&self, records: &[(usize, u64)], progress: &mut dyn FnMut(usize, usize)) {
+        for (done, &(ptr, seq)) in records.iter().enumerate() {
+            if done > 0 && done % RESOLVE_PROGRESS_STEP == 0 {
+                progress(done, records.len());
+            }
+            let frames = match self.inner.allocates.lock().get(&ptr) {
+                Some(record) if record.seq == seq && !record.symbolized => record.frames.clone(),
+                _ => continue,
+            };
+            // Symbolizing is slow, don't hold the lock meanwhile.
+            let mut stack = self.resolve_cached(&frames);
+            self.drop_locations(&mut stack);
+            if let Some(record) = self.inner.allocates.lock().get_mut(&ptr) {
+                if record.seq == seq {
+                    record.stack = stack;
+                    record.symbolized = true;
+                }
+            }
+        }
+        progress(records.len(), records.len());
+    }
+
+    /// Like [`resolve_frames`], looking the frames up in the symbol cache
+    /// first and adding those that weren't.
+    fn resolve_cached(&self, frames: &[usize]) -> HeaplessVec<Call<NAME_LEN>, STACK_SIZE> {
+        let mut stack = HeaplessVec::new();
+        for &ip in frames {
+            let cached = self.inner.symbol_cache.lock().get(&ip).cloned();
+            let calls = cached.unwrap_or_else(|| {
+                let mut calls = Vec::new_in(System);
+                calls.extend(resolve_frames::<STACK_SIZE, NAME_LEN>(
+                    &[ip],
+                    Some(&self.inner.symbols.read()),
+                ));
+                self.inner.symbol_cache.lock().insert(ip, calls.clone());
+                calls
+            });
+            for call in calls {
+                let _ = stack.push(call);
+            }
+            if stack.is_full() {
+                break;
+            }
+        }
+        stack
+    }
+
+    /// Drops the cached symbols of frames no live record has anymore, see
+    /// [`LeakTracer::symbol_cache_len`]. The cache only grows otherwise, so
+    /// long-running processes resolving lazily may call this now and then.
+    pub fn prune_symbol_cache(&self) {
+        let _guard = ReentrancyGuard::enter();
+        let mut live: HashSet<usize, DefaultHashBuilder, System> = HashSet::default();
+        for record in self.inner.allocates.lock().values() {
+            live.extend(record.frames.iter().copied());
+        }
+        self.inner
+            .symbol_cache
+            .lock()
+            .retain(|ip, _| live.contains(ip));
+    }
+
+    /// How many frame addresses have their symbols cached, for symbolizing
+    /// the records captured in [`ResolveMode::Lazy`].
+    pub fn symbol_cache_len(&self) -> usize {
+        self.inner.symbol_cache.lock().len()
+    }
+
+    /// Empties the source locations of `stack` with
+    /// [`LeakTracerBuilder::names_only`].
+    fn drop_locations(&self, stack: &mut HeaplessVec<Call<NAME_LEN>, STACK_SIZE>) {
+        if !self.options.names_only {
+            return;
+        }
+        for call in stack.iter_mut() {
+            call.filename = None;
+            call.line = None;
+            call.col = None;
+        }
+    }
+
+    /// Groups the live allocations by [`AllocationRecord::thread_id`], giving
+    /// `(count, bytes)` for each thread.
+    pub fn leaks_by_thread(&self) -> HashMap<u64, (usize, usize), DefaultHashBuilder, System> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        let mut out: HashMap<u64, (usize, usize), DefaultHashBuilder, System> = HashMap::default();
+        for record in self.inner.allocates.lock().values() {
+            let entry = out.entry(record.thread_id).or_default();
+            entry.0 += record.multiplicity;
+            entry.1 += record.size * record.multiplicity;
+        }
+        out
+    }
+
+    /// Whether a live allocation has a frame whose symbol name or filename
+    /// contains `needle`.
+    pub fn any_leak_with_symbol(&self, needle: &str) -> bool {
+        self.count_leaks_with_symbol(needle) > 0
+    }
+
+    /// How many live allocations have a frame whose symbol name or filename
+    /// contains `needle`. Lazy records are resolved first.
+    pub fn count_leaks_with_symbol(&self, needle: &str) -> usize {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| record.stack.iter().any(|call| call.matches(needle)))
+            .count()
+    }
+
+    /// Returns the live allocations that have a frame from a file whose path
+    /// contains `needle`, e.g. a module's file when the function isn't known.
+    pub fn leaks_matching_file(&self, needle: &str) -> Vec<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| {
+                record.stack.iter().any(|call| {
+                    call.filename
+                        .as_ref()
+                        .is_some_and(|filename| filename.contains(needle))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Bytes held by the live allocations that have a frame whose symbol name
+    /// or filename contains `needle`. Lazy records are resolved first.
+    pub fn leak_bytes_for_symbol(&self, needle: &str) -> usize {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| record.stack.iter().any(|call| call.matches(needle)))
+            .map(|record| record.size * record.multiplicity)
+            .sum()
+    }
+
+    /// How many live allocations have the given
+    /// [`fingerprint`](AllocationRecord::fingerprint), e.g. one taken from a
+    /// report, without grouping the others.
+    pub fn count_for_fingerprint(&self, fingerprint: u64) -> usize {
+        self.totals_for_fingerprint(fingerprint).0
+    }
+
+    /// Bytes held by the live allocations with the given fingerprint, see
+    /// [`count_for_fingerprint`](Self::count_for_fingerprint).
+    pub fn bytes_for_fingerprint(&self, fingerprint: u64) -> usize {
+        self.totals_for_fingerprint(fingerprint).1
+    }
+
+    fn totals_for_fingerprint(&self, fingerprint: u64) -> (usize, usize) {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        self.inner
+            .allocates
+            .lock()
+            .values()
+            .filter(|record| record.fingerprint() == fingerprint)
+            .fold((0, 0), |(count, bytes), record| {
+                (
+                    count + record.multiplicity,
+                    bytes + record.size * record.multiplicity,
+                )
+            })
+    }
+
+    /// Groups the live allocations by [`AllocationRecord::label`], giving
+    /// `(count, bytes)` for each label. Unlabeled ones are under `""`.
+    pub fn leaks_by_label(
+        &self,
+    ) -> HashMap<HeaplessString<LABEL_LEN>, (usize, usize), DefaultHashBuilder, System> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        let mut out: HashMap<_, (usize, usize), DefaultHashBuilder, System> = HashMap::default();
+        for record in self.inner.allocates.lock().values() {
+            let entry = out
+                .entry(record.label.clone().unwrap_or_default())
+                .or_default();
+            entry.0 += record.multiplicity;
+            entry.1 += record.size * record.multiplicity;
+        }
+        out
+    }
+
+    /// Groups the live allocations by the name of their
+    /// [`AllocationRecord::top_frame`], giving `(count, bytes)` for each.
+    /// A coarser view than [`LeakTracer::report`]: the rest of the stack is
+    /// ignored. Allocations without a named frame are under `""`.
+    pub fn leaks_by_top_frame(
+        &self,
+    ) -> HashMap<HeaplessString<NAME_LEN>, (usize, usize), DefaultHashBuilder, System> {
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let mut out: HashMap<_, (usize, usize), DefaultHashBuilder, System> = HashMap::default();
+        for record in self.inner.allocates.lock().values() {
+            let name = record.top_frame().and_then(|call| call.name.clone());
+            let entry = out.entry(name.unwrap_or_default()).or_default();
+            entry.0 += record.multiplicity;
+            entry.1 += record.size * record.multiplicity;
+        }
+        out
+    }
+
+    /// Returns the call sites with more live allocations than at the previous
+    /// call, and remembers the current counts for the next one. On the first
+    /// call every live site counts as grown.
+    pub fn growing_sites(&self) -> Vec<GrowingSite<STACK_SIZE, NAME_LEN>> {
+        let _guard = ReentrancyGuard::enter();
+        self.sites_grown_since(&mut self.inner.site_counts.lock())
+    }
+
+    /// [`LeakTracer::growing_sites`] against the counts in `previous_counts`,
+    /// which are replaced with the current ones.
+    fn sites_grown_since(
+        &self,
+        previous_counts: &mut HashMap<u64, usize, DefaultHashBuilder, System>,
+    ) -> Vec<GrowingSite<STACK_SIZE, NAME_LEN>> {
+        // A site fingerprints differently once resolved.
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+        let mut current: HashMap<
+            u64,
+            (usize, &AllocationRecord<STACK_SIZE, NAME_LEN>),
+            DefaultHashBuilder,
+            System,
+        > = HashMap::default();
+        let allocates = self.inner.allocates.lock();
+        for record in allocates.values() {
+            current.entry(record.fingerprint()).or_insert((0, record)).0 += record.multiplicity;
+        }
+
+        let mut grown = Vec::new();
+        for (fingerprint, (count, sample)) in current.iter() {
+            let previous = previous_counts.get(fingerprint).copied().unwrap_or(0);
+            if *count > previous {
+                grown.push(GrowingSite {
+                    fingerprint: *fingerprint,
+                    previous,
+                    current: *count,
+                    sample: (*sample).clone(),
+                });
+            }
+        }
+
+        previous_counts.clear();
+        for (fingerprint, (count, _)) in current.iter() {
+            previous_counts.insert(*fingerprint, *count);
+        }
+
+        grown
+    }
+
+    /// Takes every live record out of the tracer. Freeing those allocations
+    /// later is fine, it's just not reported anymore.
+    pub fn drain_leaks(&self) -> LeakSnapshot<STACK_SIZE, NAME_LEN> {
+        let mut allocates = self.inner.allocates.lock();
+        self.inner.dedup.lock().clear();
+        self.inner.stack_groups.lock().clear();
+        self.reset_live_bytes();
+        LeakSnapshot {
+            records: std::mem::take(&mut *allocates),
+        }
+    }
+
+    /// Puts the records of `snapshot` back into the tracer, so every query
+    /// and report can run over them: a [`SnapshotFile`] read from another
+    /// run, or a [`LeakSnapshot`] or [`TracerSnapshot`] converted with
+    /// `into()`. Meant for an otherwise unused tracer: the records keep
+    /// their addresses, which may clash with or be freed by live allocations.
+    pub fn load_into(&self, snapshot: SnapshotFile<STACK_SIZE, NAME_LEN>) {
+        let _guard = ReentrancyGuard::enter();
+        let mut allocates = self.inner.allocates.lock();
+        for record in snapshot {
+            // Marks taken from now on only see newer records.
+            self.inner.next_seq.fetch_max(record.seq, Ordering::SeqCst);
+            self.account_loaded(&record, true);
+            self.account_group(&record, true);
+            if let Some(stale) = allocates.insert(record.ptr, record) {
+                self.account_loaded(&stale, false);
+                self.account_group(&stale, false);
+            }
+        }
+        // Loaded records may stand for several allocations.
+        self.inner.peak_live_allocations.fetch_max(
+            stats_of(allocates.values()).live_allocations,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Counts or uncounts the bytes of a loaded record, which may stand for
+    /// several allocations.
+    fn account_loaded(&self, record: &AllocationRecord<STACK_SIZE, NAME_LEN>, added: bool) {
+        for _ in 0..record.multiplicity {
+            if added {
+                self.add_live_bytes(record.size);
+            } else {
+                self.sub_live_bytes(record.size);
+            }
+        }
+    }
+
+    /// Counts or uncounts a record in its group, see
+    /// [`LeakTracerBuilder::group_by_stack`]. Called with the record lock held.
+    fn account_group(&self, record: &AllocationRecord<STACK_SIZE, NAME_LEN>, added: bool) {
+        if !self.options.group_by_stack {
+            return;
+        }
+        let mut groups = self.inner.stack_groups.lock();
+        let bytes = record.size * record.multiplicity;
+        if added {
+            let group = groups.entry(record.stack_key).or_insert((0, 0));
+            group.0 += record.multiplicity;
+            group.1 += bytes;
+        } else if let Some(group) = groups.get_mut(&record.stack_key) {
+            group.0 = group.0.saturating_sub(record.multiplicity);
+            group.1 = group.1.saturating_sub(bytes);
+            if group.0 == 0 {
+                groups.remove(&record.stack_key);
+            }
+        }
+    }
+
+    /// `(count, bytes)` of the live allocations by
+    /// [`AllocationRecord::stack_key`], i.e. by the exact frames captured.
+    /// With [`LeakTracerBuilder::group_by_stack`] this copies the totals kept
+    /// up to date on every allocation and free, otherwise the records are
+    /// grouped now. Groups by address, so use [`LeakTracer::report`] to
+    /// compare across runs.
+    pub fn leaks_grouped_by_stack(
+        &self,
+    ) -> HashMap<u64, (usize, usize), DefaultHashBuilder, System> {
+        let _guard = ReentrancyGuard::enter();
+        if self.options.group_by_stack {
+            return self.inner.stack_groups.lock().clone();
+        }
+        let mut out: HashMap<u64, (usize, usize), DefaultHashBuilder, System> = HashMap::default();
+        for record in self.inner.allocates.lock().values() {
+            let group = out.entry(record.stack_key).or_insert((0, 0));
+            group.0 += record.multiplicity;
+            group.1 += record.size * record.multiplicity;
+        }
+        out
+    }
+
+    /// Bytes held by the live allocations, read without taking the record lock.
+    pub fn live_bytes(&self) -> usize {
+        self.inner.live_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Bytes held by the live allocations of each power-of-two size class, as
+    /// `(class, bytes)` from the smallest class up, where a class holds the
+    /// sizes above the previous one up to `class`. Kept up to date as blocks
+    /// come and go, so this doesn't take the record lock either. Empty
+    /// classes are left out.
+    pub fn bytes_by_size_class(&self) -> Vec<(usize, usize)> {
+        let _guard = ReentrancyGuard::enter();
+        self.inner
+            .class_bytes
+            .iter()
+            .enumerate()
+            .map(|(class, bytes)| (class_size(class), bytes.load(Ordering::SeqCst)))
+            .filter(|(_, bytes)| *bytes > 0)
+            .collect()
+    }
+
+    /// The live allocations by power-of-two size class, as `(class, count,
+    /// bytes)` like [`LeakTracer::bytes_by_size_class`], a cheap stand-in
+    /// for the kind of object. Only looks at sizes, so nothing is resolved
+    /// and records without stacks count too.
+    pub fn leaks_by_size_class(&self) -> Vec<(usize, usize, usize)> {
+        let _guard = ReentrancyGuard::enter();
+        let mut classes = [(0, 0); SIZE_CLASSES];
+        for record in self.inner.allocates.lock().values() {
+            count_size_class(&mut classes, record);
+        }
+        size_class_totals(&classes)
+    }
+
+    /// [`LeakTracer::leaks_by_size_class`] for each
+    /// [`AllocationRecord::thread_id`], to tell which thread makes which
+    /// kind of object.
+    pub fn size_class_by_thread(
+        &self,
+    ) -> HashMap<u64, Vec<(usize, usize, usize)>, DefaultHashBuilder, System> {
+        let _guard = ReentrancyGuard::enter();
+        let mut threads: HashMap<u64, [(usize, usize); SIZE_CLASSES], DefaultHashBuilder, System> =
+            HashMap::default();
+        for record in self.inner.allocates.lock().values() {
+            let classes = threads
+                .entry(record.thread_id)
+                .or_insert([(0, 0); SIZE_CLASSES]);
+            count_size_class(classes, record);
+        }
+        threads
+            .iter()
+            .map(|(thread, classes)| (*thread, size_class_totals(classes)))
+            .collect()
+    }
+
+    fn add_live_bytes(&self, size: usize) {
+        self.inner.live_bytes.fetch_add(size, Ordering::SeqCst);
+        self.inner.class_bytes[size_class(size)].fetch_add(size, Ordering::SeqCst);
+    }
+
+    fn sub_live_bytes(&self, size: usize) {
+        self.inner.live_bytes.fetch_sub(size, Ordering::SeqCst);
+        self.inner.class_bytes[size_class(size)].fetch_sub(size, Ordering::SeqCst);
+    }
+
+    /// Counts a block in the live totals without a record, see
+    /// [`LeakTracerBuilder::store_records`].
+    fn add_unrecorded(&self, size: usize) {
+        self.inner
+            .unrecorded_allocations
+            .fetch_add(1, Ordering::SeqCst);
+        self.add_live_bytes(size);
+    }
+
+    /// Undoes [`add_unrecorded`](Self::add_unrecorded). Saturates rather than
+    /// wrap for blocks that were allocated while tracking was off.
+    fn sub_unrecorded(&self, size: usize) {
+        let saturating_sub = |counter: &AtomicUsize, by: usize| {
+            let _ = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_sub(by))
+            });
+        };
+        saturating_sub(&self.inner.unrecorded_allocations, 1);
+        saturating_sub(&self.inner.live_bytes, size);
+        saturating_sub(&self.inner.class_bytes[size_class(size)], size);
+    }
+
+    fn reset_live_bytes(&self) {
+        self.inner.live_bytes.store(0, Ordering::SeqCst);
+        self.inner.unrecorded_allocations.store(0, Ordering::SeqCst);
+        for bytes in self.inner.class_bytes.iter() {
+            bytes.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether `ptr` is a tracked allocation that wasn't freed yet. False for
+    /// freed blocks and ones never seen, e.g. allocated while tracking was off.
+    /// With [`LeakTracerBuilder::allocation_profile`] freed blocks stay live.
+    pub fn is_live(&self, ptr: usize) -> bool {
+        let allocates = self.inner.allocates.lock();
+        allocates.contains_key(&ptr)
+            || (self.options.deduplicate && self.inner.dedup.lock().is_member(ptr))
+    }
+
+    /// Whether no allocation is live, without copying the records.
+    pub fn is_empty(&self) -> bool {
+        self.inner.allocates.lock().is_empty()
+    }
+
+    /// How many records the map can hold before it has to grow. Compare with
+    /// [`map_len`](Self::map_len) to see how close a rehash is.
+    pub fn map_capacity(&self) -> usize {
+        self.inner.allocates.lock().capacity()
+    }
+
+    /// How many records the map holds.
+    pub fn map_len(&self) -> usize {
+        self.inner.allocates.lock().len()
+    }
+
+    fn insert_record(&self, record: AllocationRecord<STACK_SIZE, NAME_LEN>) {
+        if self.options.allocation_profile {
+            let mut sites = self.inner.profile_sites.lock();
+            let site = sites.entry(record.stack_key).or_default();
+            site.0 += 1;
+            site.1 += record.size;
+        }
+        self.place_record(record);
+    }
+
+    /// Inserts `record`, keeping to
+    /// [`LeakTracerBuilder::max_profile_records`].
+    fn place_record(&self, record: AllocationRecord<STACK_SIZE, NAME_LEN>) {
+        if self.options.allocation_profile && self.options.max_profile_records != usize::MAX {
+            let newest = (record.ptr, record.seq);
+            self.insert_unbounded(record);
+            self.evict_profile_records(newest);
+        } else {
+            self.insert_unbounded(record);
+        }
+    }
+
+    fn insert_unbounded(&self, record: AllocationRecord<STACK_SIZE, NAME_LEN>) {
+        if self.options.deduplicate {
+            return self.insert_deduplicated(record);
+        }
+        let mut allocates = self.inner.allocates.lock();
+        self.add_live_bytes(record.size);
+        self.account_group(&record, true);
+        // The address can be reused after a free we didn't see, e.g. while disabled.
+        if let Some(stale) = allocates.insert(record.ptr, record) {
+            self.sub_live_bytes(stale.size);
+            self.account_group(&stale, false);
+        }
+        self.inner
+            .peak_live_allocations
+            .fetch_max(allocates.len(), Ordering::Relaxed);
+    }
+
+    /// Drops the oldest records of the profile past
+    /// [`LeakTracerBuilder::max_profile_records`], now that `newest` was
+    /// added.
+    fn evict_profile_records(&self, newest: (usize, u64)) {
+        let max = self.options.max_profile_records;
+        let is_current = |(ptr, seq): (usize, u64)| {
+            let allocates = self.inner.allocates.lock();
+            allocates.get(&ptr).map(|record| record.seq) == Some(seq)
+        };
+        let mut order = self.inner.profile_order.lock();
+        order.push_back(newest);
+        while self.map_len() > max {
+            let Some(oldest) = order.pop_front() else {
+                break;
+            };
+            if is_current(oldest) {
+                self.remove_record(oldest.0);
+            }
+        }
+        // Records replaced at a reused address leave entries behind.
+        if order.len() > 2 * max.max(1) {
+            order.retain(|entry| is_current(*entry));
+        }
+    }
+
+    /// Every allocation counted in
+    /// [`allocation_profile`](LeakTracerBuilder::allocation_profile) mode,
+    /// as `(count, bytes)` by [`AllocationRecord::stack_key`], including the
+    /// ones whose record is gone.
+    pub fn profile_sites(&self) -> HashMap<u64, (usize, usize), DefaultHashBuilder, System> {
+        self.inner.profile_sites.lock().clone()
+    }
+
+    /// Carries the record of a reallocated block over to its new address and
+    /// size, keeping the original stack. Returns false if it wasn't tracked.
+    /// Never inlined, like `record_allocation`, so stacks taken here skip as
+    /// many frames as the ones [`LeakTracer::frame_skip`] is counted on.
+    #[inline(never)]
+    fn resize_record(&self, old_ptr: *mut u8, new_ptr: *mut u8, new_size: usize) -> bool {
+        let Some(mut record) = self.remove_record(old_ptr as usize) else {
+            return false;
+        };
+        if self.options.last_realloc_stack && new_ptr != old_ptr {
+            let mut stack = HeaplessVec::new();
+            let mut frames = HeaplessVec::new();
+            let depth = self.capture_depth.load(Ordering::Relaxed);
+            self.trace_into(depth, true, &mut stack, &mut frames);
+            self.drop_locations(&mut stack);
+            record.last_realloc_stack = Some(Box::new_in(stack, System));
+        }
+        record.ptr = new_ptr as usize;
+        record.size = new_size;
+        record.usable_size = usable_size(new_ptr, new_size);
+        record.realloc_count += 1;
+        record.origin = AllocOrigin::Realloc;
+        let queued = (!record.symbolized).then_some((record.ptr, record.seq));
+        // Not a new allocation of its site.
+        self.place_record(record);
+        if let Some(queued) = queued {
+            if self.inner.resolver_running.load(Ordering::SeqCst) {
+                self.inner.resolve_queue.lock().push(queued);
+            }
+        }
+        true
+    }
+
+    fn remove_record(&self, ptr: usize) -> Option<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        if self.options.deduplicate {
+            return self.remove_deduplicated(ptr);
+        }
+        let mut allocates = self.inner.allocates.lock();
+        let removed = allocates.remove(&ptr);
+        if let Some(record) = &removed {
+            self.sub_live_bytes(record.size);
+            self.account_group(record, false);
+        }
+        removed
+    }
+
+    /// Forgets the records of `ptrs` taking the record lock once, for
+    /// allocators that release many blocks at once (e.g. an arena reset)
+    /// and tell the tracer themselves. Returns how many were tracked. Like
+    /// [`LeakTracer::clear`] this doesn't count as frees.
+    pub fn remove_records(&self, ptrs: &[usize]) -> usize {
+        if self.options.deduplicate {
+            return self.remove_many_deduplicated(ptrs);
+        }
+        let mut allocates = self.inner.allocates.lock();
+        let mut removed = 0;
+        for ptr in ptrs {
+            if let Some(record) = allocates.remove(ptr) {
+                self.sub_live_bytes(record.size);
+                self.account_group(&record, false);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn fire_event(&self, event: AllocEvent) {
+        let capacity = self.options.event_log;
+        if capacity > 0 {
+            let mut log = self.inner.event_log.lock();
+            if log.len() == capacity {
+                log.pop_front();
+            }
+            if log.capacity() == 0 {
+                // Allocated once, at the first event.
+                log.reserve_exact(capacity);
+            }
+            log.push_back(LoggedEvent {
+                seq: self.inner.logged_events.fetch_add(1, Ordering::Relaxed) + 1,
+                event,
+            });
+        }
+        // Off the lock, the hook may replace itself.
+        let hook = self.inner.event_hook.read().clone();
+        if let Some(hook) = hook {
+            hook(event);
+        }
+    }
+
+    /// How many frames at the top of a captured stack are the tracer's own,
+    /// up to and including [`GlobalAlloc::alloc`], so the first one kept is
+    /// its caller. Calibrated once, on the first tracked allocation, by
+    /// counting the frames above a known caller of `alloc`; the count only
+    /// depends on how the tracer itself was compiled. Falls back to 3 if
+    /// that caller can't be found, e.g. where frames don't carry their
+    /// function's address.
+    pub fn frame_skip(&self) -> usize {
+        self.inner
+            .frame_skip
+            .get()
+            .copied()
+            .unwrap_or(DEFAULT_FRAME_SKIP)
+    }
+
+    /// Makes the allocation [`LeakTracer::frame_skip`] is counted on.
+    fn calibrate_frame_skip(&self) {
+        let inside = IN_TRACER.try_with(Cell::get).unwrap_or(true);
+        // Nested in the sentinel's own allocation, or the sentinel couldn't be tracked.
+        if inside || CALIBRATING.with(|calibrating| calibrating.replace(true)) {
+            return;
+        }
+        calibration_sentinel(self);
+        CALIBRATING.with(|calibrating| calibrating.set(false));
+        // Don't try again if the frames didn't give it away.
+        let _ = self.inner.frame_skip.set(DEFAULT_FRAME_SKIP);
+    }
+
+    /// Finds the sentinel among the frames above it. Always inlined into
+    /// `record_allocation` like `trace_into`, so both see the same frames.
+    #[inline(always)]
+    fn count_own_frames(&self) {
+        let sentinel = calibration_sentinel::<STACK_SIZE, NAME_LEN> as fn(&Self) as usize;
+        let mut index = 0;
+        let mut found = None;
+        unsafe {
+            backtrace::trace_unsynchronized(|frame| {
+                if frame.symbol_address() as usize == sentinel {
+                    found = Some(index);
+                    return false;
+                }
+                index += 1;
+                index < 64
+            });
+        }
+        if let Some(skip) = found {
+            let _ = self.inner.frame_skip.set(skip);
+        }
+    }
+
+    /// Walks the calling thread's stack into `frames`, and into `stack` too
+    /// when `resolve`. Always inlined so the frames to skip stay the same.
+    #[inline(always)]
+    fn trace_into(
+        &self,
+        depth: usize,
+        resolve: bool,
+        stack: &mut HeaplessVec<Call<NAME_LEN>, STACK_SIZE>,
+        frames: &mut HeaplessVec<usize, STACK_SIZE>,
+    ) {
+        // The top frames are in the closure and the accounting itself, meaningless, skip that.
+        let mut skip_count = self.frame_skip();
+        let symbols = self.inner.symbols.read();
+        let excluded = self.inner.excluded_ranges.read();
+        // On win7 64, it's may cause deadlock, solution is to palce a newer version of dbghelp.dll combined with exe
+        unsafe {
+            backtrace::trace_unsynchronized(|frame| {
+                if skip_count > 0 {
+                    skip_count -= 1;
+                    return true;
+                }
+
+                if depth == 0 {
+                    return false;
+                }
+                let ip = frame.ip() as usize;
+                if excluded
+                    .iter()
+                    .any(|(start, end)| (*start..*end).contains(&ip))
+                {
+                    return true;
+                }
+                let _ = frames.push(ip);
+                if !resolve {
+                    return frames.len() < depth;
+                }
+
+                // A frame may resolve to several inlined symbols, keep what fits.
+                let from = stack.len();
+                backtrace::resolve_frame_unsynchronized(frame, |symbol| {
+                    if stack.len() < depth {
+                        let _ = stack.push(Call {
+                            ip,
+                            ..symbol.into()
+                        });
+                    }
+                });
+                name_from_registered(stack, from, ip, &symbols, depth);
+                stack.len() < depth && !frames.is_full()
+            });
+        }
+    }
+
+    /// Returns whether the allocation got a record.
+    #[inline(never)]
+    fn record_allocation(
+        &self,
+        size: usize,
+        align: usize,
+        ptr: *mut u8,
+        origin: AllocOrigin,
+    ) -> bool {
+        if self.inner.frame_skip.get().is_none() && CALIBRATING.try_with(Cell::get).unwrap_or(false)
+        {
+            self.count_own_frames();
+            return false;
+        }
+        let lazy = self.options.resolve_mode == ResolveMode::Lazy;
+        let eager = self.options.resolve_mode == ResolveMode::Eager;
+        let mut stack = HeaplessVec::default();
+        let mut frames = HeaplessVec::default();
+        let depth = self.capture_depth.load(Ordering::Relaxed);
+        let captures = if self.options.capture_budget == usize::MAX {
+            0
+        } else {
+            self.inner.captures.fetch_add(1, Ordering::Relaxed)
+        };
+        let within_budget = captures < self.options.capture_budget;
+        if self.skips_over_budget(captures) {
+            return false;
+        }
+        let provided = within_budget && self.provide_backtrace(depth, &mut stack, &mut frames);
+        if within_budget && !provided {
+            self.trace_into(depth, eager, &mut stack, &mut frames);
+        }
+
+        if self.options.resolve_mode == ResolveMode::ModuleOffset && !provided {
+            stack = self.module_offsets(&frames);
+        }
+        self.drop_locations(&mut stack);
+
+        // Over budget, there is nothing to resolve, nor did capturing fail.
+        let mut symbolized = !lazy || !within_budget || provided;
+        if within_budget && frames.is_empty() {
+            match self.options.on_capture_failure {
+                CaptureFailure::Record => {}
+                CaptureFailure::Skip => return false,
+                CaptureFailure::Sentinel => {
+                    let _ = stack.push(Call {
+                        name: Some(truncated(UNWIND_FAILED)),
+                        filename: None,
+                        line: None,
+                        col: None,
+                        addr: 0,
+                        ip: 0,
+                        module: None,
+                        offset: 0,
+                    });
+                    symbolized = true;
+                }
+            }
+        }
+
+        let (thread_id, thread_name) = THREAD_INFO
+            .try_with(|info| {
+                let info = info.get_or_init(|| {
+                    let thread = std::thread::current();
+                    ThreadInfo {
+                        id: thread.id().as_u64().get(),
+                        name: thread.name().map(truncated),
+                    }
+                });
+                (info.id, info.name.clone())
+            })
+            .unwrap_or((0, None));
+
+        let label = LABELS
+            .try_with(|labels| labels.try_borrow().ok()?.last().cloned())
+            .ok()
+            .flatten();
+
+        let stack_key = stack_key(&frames, &stack);
+        let allocation_record = AllocationRecord {
+            size,
+            usable_size: usable_size(ptr, size),
+            align,
+            ptr: ptr as usize,
+            stack,
+            frames,
+            symbolized,
+            thread_id,
+            thread_name,
+            label,
+            seq: self.inner.next_seq.fetch_add(1, Ordering::SeqCst) + 1,
+            allocated_at: self.now(),
+            origin,
+            realloc_count: 0,
+            multiplicity: 1,
+            stack_key,
+            last_realloc_stack: None,
+        };
+        if !self.admit_call_site(&allocation_record) {
+            return false;
+        }
+        let queued = (allocation_record.ptr, allocation_record.seq);
+        self.insert_record(allocation_record);
+        if lazy && self.inner.resolver_running.load(Ordering::SeqCst) {
+            self.inner.resolve_queue.lock().push(queued);
+        }
+        true
+    }
+
+    #[inline(never)]
+    fn alloc_accounting(&self, layout: Layout, ptr: *mut u8) -> *mut u8 {
+        if !self.tracking() {
+            return ptr;
+        }
+        if self.inner.frame_skip.get().is_none() {
+            if CALIBRATING.try_with(Cell::get).unwrap_or(false) {
+                // The sentinel's, only there to be counted.
+                if let Some(_guard) = ReentrancyGuard::enter() {
+                    self.record_allocation(layout.size(), layout.align(), ptr, AllocOrigin::Alloc);
+                }
+                return ptr;
+            }
+            self.calibrate_frame_skip();
+        }
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            return ptr;
+        };
+
+        let size = layout.size();
+        self.inner.alloc_count.fetch_add(1, Ordering::Relaxed);
+        let counted = if !self.options.store_records {
+            self.add_unrecorded(size);
+            true
+        } else {
+            self.admits(size)
+                && !self.sampled_out(size)
+                && self.record_allocation(size, layout.align(), ptr, AllocOrigin::Alloc)
+        };
+        self.count_rate(size);
+        if counted {
+            self.fire_event(AllocEvent::Alloc {
+                ptr: ptr as usize,
+                size,
+            });
+        }
+
+        ptr
+    }
+
+    #[inline(never)]
+    fn realloc_accounting(
+        &self,
+        old_ptr: *mut u8,
+        old_layout: Layout,
+        new_ptr: *mut u8,
+        new_size: usize,
+    ) {
+        if !self.tracking() {
+            return;
+        }
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            return;
+        };
+
+        let old_size = old_layout.size();
+        if new_size > old_size {
+            self.inner
+                .realloc_grow_bytes
+                .fetch_add(new_size - old_size, Ordering::Relaxed);
+        } else {
+            self.inner
+                .realloc_shrink_bytes
+                .fetch_add(old_size - new_size, Ordering::Relaxed);
+        }
+        let resized = if !self.options.store_records {
+            // Whether the block moved or not, it's the same one resized.
+            self.sub_unrecorded(old_size);
+            self.add_unrecorded(new_size);
+            true
+        } else {
+            self.resize_record(old_ptr, new_ptr, new_size)
+        };
+        if resized {
+            self.fire_event(AllocEvent::Realloc {
+                old_ptr: old_ptr as usize,
+                new_ptr: new_ptr as usize,
+                old_size,
+                new_size,
+            });
+        } else if new_ptr != old_ptr
+            && self.admits(new_size)
+            && !self.sampled_out(new_size)
+            // Reallocation keeps the alignment.
+            && self.record_allocation(new_size, old_layout.align(), new_ptr, AllocOrigin::Realloc)
+        {
+            // New to the tracer, as if just allocated.
+            self.fire_event(AllocEvent::Alloc {
+                ptr: new_ptr as usize,
+                size: new_size,
+            });
+        }
+    }
+
+    /// Returns false for a double free, which mustn't reach `System`.
+    fn dealloc_accounting(&self, ptr: *mut u8, size: usize) -> bool {
+        if !self.tracking() {
+            return true;
+        }
+        let Some(_guard) = ReentrancyGuard::enter() else {
+            return true;
+        };
+
+        self.inner.free_count.fetch_add(1, Ordering::Relaxed);
+        if self.options.allocation_profile {
+            // The record stays, but its block is gone. Only a hook needs to
+            // know whether it was one of them, frees don't look otherwise.
+            if self.inner.event_hook.read().is_some()
+                && self.inner.allocates.lock().contains_key(&(ptr as usize))
+            {
+                self.fire_event(AllocEvent::Free {
+                    ptr: ptr as usize,
+                    size,
+                });
+            }
+            return true;
+        }
+        if !self.options.store_records {
+            self.sub_unrecorded(size);
+            self.fire_event(AllocEvent::Free {
+                ptr: ptr as usize,
+                size,
+            });
+        } else if let Some(record) = self.remove_record(ptr as usize) {
+            let lifetime = self.now().saturating_sub(record.allocated_at);
+            self.inner
+                .lifetime_nanos
+                .fetch_add(lifetime.as_nanos() as u64, Ordering::Relaxed);
+            self.inner.lifetimes.fetch_add(1, Ordering::Relaxed);
+            self.freed_block(ptr);
+            self.fire_event(AllocEvent::Free {
+                ptr: ptr as usize,
+                size,
+            });
+        } else if self.is_double_free(ptr, size) {
+            return false;
+        }
+        true
+    }
+}
+
+unsafe impl<const STACK_SIZE: usize, const NAME_LEN: usize> GlobalAlloc
+    for LeakTracer<STACK_SIZE, NAME_LEN>
+{
+    // The frames above a captured stack have to be the same on every path,
+    // whatever the optimizer makes of them.
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.detached.load(Ordering::Relaxed) {
+            return System.alloc(layout);
+        }
+        let ptr = System.alloc(layout);
+        // Tracked or not, the block isn't freed anymore.
+        self.reused_block(ptr);
+        self.alloc_accounting(layout, ptr)
+    }
+
+    #[inline(never)]
+    unsafe fn realloc(&self, ptr0: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let ptr = System.realloc(ptr0, layout, new_size);
+        if !ptr.is_null() && !self.detached.load(Ordering::Relaxed) {
+            self.reused_block(ptr);
+            self.realloc_accounting(ptr0, layout, ptr, new_size);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.detached.load(Ordering::Relaxed) || self.dealloc_accounting(ptr, layout.size()) {
+            System.dealloc(ptr, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        stack_key, AllocEvent, AllocOrigin, AllocationRecord, Call, LeakTracer, ReportOptions,
+        ResolveMode,
+    };
+    use heapless::String as HeaplessString;
+    use heapless::Vec as HeaplessVec;
+    use std::alloc::{GlobalAlloc, Layout};
+    use std::hash::{Hash, Hasher};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Defines a helper calling the tracer from a frame of its own, so
+    /// stacks show it under its name whatever the optimizations.
+    macro_rules! frame_fn {
+        (fn $name:ident($($arg:ident: $ty:ty),*) -> *mut u8 $body:block) => {
+            #[inline(never)]
+            fn $name($($arg: $ty),*) -> *mut u8 {
+                // Not a tail call, which would take this frame off the stack.
+                std::hint::black_box($body)
+            }
+        };
+    }
+    pub(crate) use frame_fn;
+
+    pub(crate) fn fake_call(name: &str) -> Call {
+        Call {
+            name: Some(HeaplessString::from(name)),
+            filename: Some(HeaplessString::from("src/fake.rs")),
+            line: Some(1),
+            col: Some(1),
+            addr: 0,
+            ip: 0,
+            module: None,
+            offset: 0,
+        }
+    }
+
+    pub(crate) fn fake_record<const STACK_SIZE: usize>(
+        ptr: usize,
+        size: usize,
+        name: &str,
+    ) -> AllocationRecord<STACK_SIZE> {
+        let mut stack = HeaplessVec::new();
+        stack.push(fake_call(name)).unwrap();
+        AllocationRecord {
+            size,
+            usable_size: size,
+            align: 8,
+            ptr,
+            frames: HeaplessVec::new(),
+            symbolized: true,
+            thread_id: 1,
+            thread_name: None,
+            label: None,
+            seq: 0,
+            allocated_at: Duration::ZERO,
+            origin: AllocOrigin::Alloc,
+            realloc_count: 0,
+            multiplicity: 1,
+            stack_key: stack_key(&HeaplessVec::<usize, STACK_SIZE>::new(), &stack),
+            stack,
+            last_realloc_stack: None,
+        }
+    }
+
+    pub(crate) fn inject<const STACK_SIZE: usize>(
+        tracer: &LeakTracer<STACK_SIZE>,
+        record: AllocationRecord<STACK_SIZE>,
+    ) {
+        tracer.insert_record(record);
+    }
+
+    /// Frees everything still tracked.
+    fn free_all<const STACK_SIZE: usize, const NAME_LEN: usize>(
+        tracer: &LeakTracer<STACK_SIZE, NAME_LEN>,
+    ) {
+        for (ptr, record) in tracer.get_leaks() {
+            let layout = Layout::from_size_align(record.size, record.align).unwrap();
+            unsafe { tracer.dealloc(ptr as *mut u8, layout) };
+        }
+    }
+
+    #[test]
+    fn it_works() {
+        let _ = crate::LeakTracer::<15>::new();
+    }
+
+    #[test]
+    fn event_hook_sees_every_tracked_event() {
+        let tracer = LeakTracer::<5>::new();
+        let counts = Arc::new([
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        ]);
+        let hook_counts = counts.clone();
+        tracer.set_event_hook(move |event| {
+            let idx = match event {
+                AllocEvent::Alloc { .. } => 0,
+                AllocEvent::Free { .. } => 1,
+                AllocEvent::Realloc { .. } => 2,
+            };
+            hook_counts[idx].fetch_add(1, Ordering::SeqCst);
+        });
+
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let a = tracer.alloc(layout);
+            let b = tracer.alloc(layout);
+            let a = tracer.realloc(a, layout, 256);
+            tracer.dealloc(b, layout);
+            tracer.dealloc(a, Layout::from_size_align(256, 8).unwrap());
+        }
+
+        let seen: Vec<usize> = counts.iter().map(|c| c.load(Ordering::SeqCst)).collect();
+        assert_eq!(seen, [2, 2, 1]);
+        assert!(tracer.get_leaks().is_empty());
+    }
+
+    #[test]
+    fn event_stream_balances_under_filters() {
+        let tracer = Arc::new(LeakTracer::<5>::builder().min_size(32).build());
+        let balance = Arc::new(std::sync::atomic::AtomicIsize::new(0));
+        let hook_balance = balance.clone();
+        let hook_tracer = tracer.clone();
+        tracer.set_event_hook(move |event| {
+            match event {
+                AllocEvent::Alloc { .. } => hook_balance.fetch_add(1, Ordering::SeqCst),
+                AllocEvent::Free { .. } => hook_balance.fetch_sub(1, Ordering::SeqCst),
+                AllocEvent::Realloc { .. } => 0,
+            };
+            // Replacing the hook from inside it doesn't deadlock.
+            hook_tracer.clear_event_hook();
+            let hook_balance = hook_balance.clone();
+            hook_tracer.set_event_hook(move |event| match event {
+                AllocEvent::Alloc { .. } => {
+                    hook_balance.fetch_add(1, Ordering::SeqCst);
+                }
+                AllocEvent::Free { .. } => {
+                    hook_balance.fetch_sub(1, Ordering::SeqCst);
+                }
+                AllocEvent::Realloc { .. } => {}
+            });
+        });
+
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let large = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let a = tracer.alloc(small);
+            let b = tracer.alloc(large);
+            assert_eq!(balance.load(Ordering::SeqCst), 1);
+            // Growing past the filter records it, as an allocation.
+            let a = tracer.realloc(a, small, 4096);
+            tracer.dealloc(b, large);
+            tracer.dealloc(a, Layout::from_size_align(4096, 8).unwrap());
+        }
+        assert_eq!(balance.load(Ordering::SeqCst), 0);
+        tracer.clear_event_hook();
+    }
+
+    #[test]
+    fn growing_sites_reports_only_sites_that_grew() {
+        let tracer = LeakTracer::<5>::new();
+        inject(&tracer, fake_record(0x10, 8, "steady"));
+        inject(&tracer, fake_record(0x20, 8, "growing"));
+        assert_eq!(tracer.growing_sites().len(), 2);
+
+        inject(&tracer, fake_record(0x30, 8, "growing"));
+        let grown = tracer.growing_sites();
+        assert_eq!(grown.len(), 1);
+        assert_eq!(grown[0].previous, 1);
+        assert_eq!(grown[0].current, 2);
+        assert_eq!(grown[0].sample.stack[0].name.as_deref(), Some("growing"));
+
+        assert!(tracer.growing_sites().is_empty());
+    }
+
+    #[test]
+    fn growing_sites_do_not_grow_by_resolving() {
+        let tracer = LeakTracer::<5>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        alloc_from_one_site(&tracer, layout);
+        assert_eq!(tracer.growing_sites().len(), 1);
+
+        tracer.resolve();
+        assert!(tracer.growing_sites().is_empty());
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn records_capture_thread_name() {
+        let tracer = LeakTracer::<5>::new();
+        let leaked = std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .name("leaky-worker".into())
+                .spawn_scoped(scope, || unsafe {
+                    tracer.alloc(Layout::from_size_align(32, 8).unwrap()) as usize
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+
+        let leaks = tracer.get_leaks();
+        let record = &leaks[&leaked];
+        assert_eq!(record.thread_name.as_deref(), Some("leaky-worker"));
+        assert_ne!(record.thread_id, 0);
+        assert!(record.to_string().contains("thread leaky-worker"));
+
+        unsafe { tracer.dealloc(leaked as *mut u8, Layout::from_size_align(32, 8).unwrap()) };
+    }
+
+    #[test]
+    fn leaks_by_thread_totals_each_thread() {
+        let tracer = LeakTracer::<5>::new();
+        let leak = |sizes: &'static [usize]| {
+            let tracer = &tracer;
+            move || {
+                for size in sizes {
+                    unsafe { tracer.alloc(Layout::from_size_align(*size, 8).unwrap()) };
+                }
+            }
+        };
+        std::thread::scope(|scope| {
+            scope.spawn(leak(&[16, 16])).join().unwrap();
+            scope.spawn(leak(&[100])).join().unwrap();
+        });
+
+        let mut totals: Vec<(usize, usize)> = tracer.leaks_by_thread().values().copied().collect();
+        totals.sort();
+        assert_eq!(totals, [(1, 100), (2, 32)]);
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn records_from_the_same_site_are_equal() {
+        let mut a = fake_record::<5>(0x10, 24, "site");
+        let mut b = fake_record::<5>(0x20, 24, "site");
+        a.stack[0].addr = 0x1000;
+        b.stack[0].addr = 0x2000;
+        b.thread_id = 7;
+        assert_eq!(a, b);
+
+        let hash = |record: &AllocationRecord<5>| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            record.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(a.fingerprint(), b.fingerprint());
+
+        assert_ne!(a, fake_record::<5>(0x10, 48, "site"));
+        assert_ne!(a, fake_record::<5>(0x10, 24, "elsewhere"));
+    }
+
+    #[test]
+    fn leaks_since_returns_only_later_allocations() {
+        let tracer = LeakTracer::<5>::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let before = unsafe { tracer.alloc(layout) };
+        let marker = tracer.mark();
+        let after = unsafe { tracer.alloc(layout) };
+
+        let since = tracer.leaks_since(&marker);
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].ptr, after as usize);
+
+        unsafe { tracer.dealloc(after, layout) };
+        assert!(tracer.leaks_since(&marker).is_empty());
+        unsafe { tracer.dealloc(before, layout) };
+    }
+
+    #[test]
+    fn filtered_leaks_are_resolved_when_lazy() {
+        let tracer = LeakTracer::<5>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let marker = tracer.mark();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        alloc_from_one_site(&tracer, layout);
+
+        for records in [
+            tracer.leaks_since(&marker),
+            tracer.leaks_with_origin(AllocOrigin::Alloc),
+            tracer.leaks_between_sizes(32, 64),
+        ]
+        .iter()
+        {
+            assert_eq!(records.len(), 1);
+            assert!(records[0].symbolized);
+            assert!(records[0]
+                .stack
+                .iter()
+                .any(|call| call.matches("alloc_from_one_site")));
+        }
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn drain_leaks_hands_over_the_records() {
+        let tracer = LeakTracer::<5>::new();
+        let mut ptrs = Vec::new();
+        for size in [10, 30, 20] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            ptrs.push((unsafe { tracer.alloc(layout) }, layout));
+        }
+
+        let snapshot = tracer.drain_leaks();
+        assert!(tracer.get_leaks().is_empty());
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.total_bytes(), 60);
+        let sizes: Vec<usize> = snapshot
+            .sorted_by_size()
+            .into_iter()
+            .map(|record| record.size)
+            .collect();
+        assert_eq!(sizes, [30, 20, 10]);
+
+        for (ptr, layout) in ptrs {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        let total: usize = tracer.drain_leaks().into_iter().map(|r| r.size).sum();
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn records_requested_and_usable_size() {
+        let tracer = LeakTracer::<5>::new();
+        let layout = Layout::from_size_align(13, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+
+        let record = tracer.get_leaks()[&(ptr as usize)].clone();
+        assert_eq!(record.size, 13);
+        assert!(record.usable_size >= 13);
+        #[cfg(target_os = "linux")]
+        assert!(record.usable_size > 13);
+
+        let stats = tracer.stats();
+        assert_eq!(stats.live_allocations, 1);
+        assert_eq!(stats.live_bytes, 13);
+        assert_eq!(stats.live_usable_bytes, record.usable_size);
+
+        unsafe { tracer.dealloc(ptr, layout) };
+        let expected = crate::Stats {
+            alloc_count: 1,
+            free_count: 1,
+            ..Default::default()
+        };
+        assert_eq!(tracer.stats(), expected);
+    }
+
+    #[test]
+    fn display_leaks_formats_every_record() {
+        let tracer = LeakTracer::<5>::new();
+        inject(&tracer, fake_record(0x10, 11, "first_site"));
+        inject(&tracer, fake_record(0x20, 22, "second_site"));
+
+        let out = tracer.display_leaks().to_string();
+        assert!(out.contains("Allocation (size 11"));
+        assert!(out.contains("Allocation (size 22"));
+        assert!(out.contains("first_site @ src/fake.rs:1"));
+        assert!(out.contains("second_site @ src/fake.rs:1"));
+    }
+
+    #[test]
+    fn live_bytes_matches_the_records() {
+        let tracer = LeakTracer::<5>::new();
+        let summed = |tracer: &LeakTracer<5>| tracer.stats().live_bytes;
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let big = Layout::from_size_align(1000, 8).unwrap();
+
+        let a = unsafe { tracer.alloc(small) };
+        let b = unsafe { tracer.alloc(big) };
+        assert_eq!(tracer.live_bytes(), 1016);
+        let a = unsafe { tracer.realloc(a, small, 4096) };
+        assert_eq!(tracer.live_bytes(), summed(&tracer));
+        unsafe { tracer.dealloc(b, big) };
+        assert_eq!(tracer.live_bytes(), summed(&tracer));
+
+        tracer.disable();
+        let untracked = unsafe { tracer.alloc(small) };
+        tracer.enable();
+        unsafe { tracer.dealloc(untracked, small) };
+        assert_eq!(tracer.live_bytes(), summed(&tracer));
+
+        unsafe { tracer.dealloc(a, Layout::from_size_align(4096, 8).unwrap()) };
+        assert_eq!(tracer.live_bytes(), 0);
+    }
+
+    frame_fn! {
+        fn alloc_from_one_site(tracer: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+            unsafe { tracer.alloc(layout) }
+        }
+    }
+
+    #[test]
+    fn lazy_resolution_matches_eager_capture() {
+        let eager = LeakTracer::<8>::new();
+        let lazy = LeakTracer::<8>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let ptrs: Vec<usize> = [&eager, &lazy]
+            .iter()
+            .map(|tracer| alloc_from_one_site(*tracer, layout) as usize)
+            .collect();
+
+        let unresolved = lazy.inner.allocates.lock()[&ptrs[1]].clone();
+        assert!(!unresolved.symbolized);
+        assert!(unresolved.stack.is_empty());
+        assert!(!unresolved.frames.is_empty());
+
+        lazy.resolve();
+        let eager_record = eager.get_leaks()[&ptrs[0]].clone();
+        let lazy_record = lazy.get_leaks()[&ptrs[1]].clone();
+        assert!(lazy_record.symbolized);
+        assert!(!lazy_record.stack.is_empty());
+        assert_eq!(eager_record.stack, lazy_record.stack);
+
+        free_all(&eager);
+        free_all(&lazy);
+    }
+
+    #[test]
+    fn largest_leak_picks_the_biggest_block() {
+        let tracer = LeakTracer::<5>::new();
+        assert!(tracer.largest_leak().is_none());
+        for (ptr, size) in [(0x10, 64), (0x20, 4096), (0x30, 512)] {
+            inject(&tracer, fake_record(ptr, size, "site"));
+        }
+
+        let largest = tracer.largest_leak().unwrap();
+        assert_eq!(largest.ptr, 0x20);
+        assert_eq!(largest.size, 4096);
+
+        // Ranked by all the allocations a deduplicated record stands for.
+        let merged = AllocationRecord {
+            multiplicity: 10,
+            ..fake_record(0x40, 1024, "merged_site")
+        };
+        inject(&tracer, merged);
+        assert_eq!(tracer.largest_leak().unwrap().ptr, 0x40);
+        tracer.clear();
+    }
+
+    #[test]
+    fn largest_leak_resolves_like_other_queries() {
+        let tracer = LeakTracer::<5>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        tracer.register_symbol(0x1000, "jit_stub");
+        let mut record = fake_record(0x10, 64, "unused");
+        record.stack.clear();
+        record.frames.push(0x1010).unwrap();
+        record.symbolized = false;
+        inject(&tracer, record);
+
+        let largest = tracer.largest_leak().unwrap();
+        assert!(largest.symbolized);
+        assert!(largest.stack.iter().any(|call| call.matches("jit_stub")));
+        // Resolved in place, not only in the copy.
+        assert!(tracer.inner.allocates.lock()[&0x10].symbolized);
+        tracer.clear();
+    }
+
+    #[test]
+    fn alloc_rate_follows_the_clock() {
+        static NOW_MS: AtomicU64 = AtomicU64::new(0);
+        fn fake_clock() -> Duration {
+            Duration::from_millis(NOW_MS.load(Ordering::SeqCst))
+        }
+
+        let tracer = LeakTracer::<2>::new();
+        tracer.set_clock(fake_clock);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        for _ in 0..4 {
+            unsafe { tracer.alloc(layout) };
+        }
+        NOW_MS.store(500, Ordering::SeqCst);
+        assert_eq!(tracer.alloc_rate(), (8.0, 2048.0));
+
+        // Past the window, the next allocation starts counting over.
+        NOW_MS.store(1500, Ordering::SeqCst);
+        unsafe { tracer.alloc(layout) };
+        NOW_MS.store(2000, Ordering::SeqCst);
+        assert_eq!(tracer.alloc_rate(), (2.0, 512.0));
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn frees_of_untracked_blocks_are_ignored() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        tracer.disable();
+        let untracked = unsafe { tracer.alloc(layout) };
+        tracer.enable();
+        let tracked = unsafe { tracer.alloc(layout) };
+
+        let frees = Arc::new(AtomicUsize::new(0));
+        let hook_frees = frees.clone();
+        tracer.set_event_hook(move |event| {
+            if let AllocEvent::Free { .. } = event {
+                hook_frees.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        unsafe { tracer.dealloc(untracked, layout) };
+        assert_eq!(frees.load(Ordering::SeqCst), 0);
+        assert_eq!(tracer.live_bytes(), 64);
+        assert_eq!(tracer.stats().live_allocations, 1);
+
+        unsafe { tracer.dealloc(tracked, layout) };
+        assert_eq!(frees.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn name_capacity_bounds_captured_strings() {
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let names = |stack: &[Call<4096>]| -> Vec<String> {
+            stack
+                .iter()
+                .filter_map(|call| call.name.as_deref().map(String::from))
+                .collect()
+        };
+
+        let short = LeakTracer::<6, 8>::new();
+        alloc_from_one_site(&short, layout);
+        let record = short.largest_leak().unwrap();
+        assert!(!record.stack.is_empty());
+        for call in record.stack.iter() {
+            assert!(call.name.as_ref().map_or(0, |name| name.len()) <= 8);
+            assert!(call.filename.as_ref().map_or(0, |name| name.len()) <= 8);
+        }
+
+        let long = LeakTracer::<6, 4096>::new();
+        alloc_from_one_site(&long, layout);
+        let record = long.largest_leak().unwrap();
+        assert!(names(&record.stack).iter().any(|name| name.len() > 8));
+
+        free_all(&short);
+        free_all(&long);
+    }
+
+    #[test]
+    fn truncation_keeps_whole_chars() {
+        let name: HeaplessString<4> = crate::truncated("añbc");
+        assert_eq!(name.as_str(), "añb");
+        let name: HeaplessString<3> = crate::truncated("añbc");
+        assert_eq!(name.as_str(), "añ");
+    }
+
+    #[test]
+    fn truncated_paths_keep_the_basename() {
+        let path = format!("/{}crates/leaky/src/allocation_site.rs", "deep/".repeat(20));
+        let filename: HeaplessString<40> = crate::truncated_path(path.chars());
+        assert_eq!(filename.as_str(), ".../crates/leaky/src/allocation_site.rs");
+
+        let short: HeaplessString<40> = crate::truncated_path("src/lib.rs".chars());
+        assert_eq!(short.as_str(), "src/lib.rs");
+        let basename: HeaplessString<8> = crate::truncated_path("/src/allocation_site.rs".chars());
+        assert_eq!(basename.as_str(), "...te.rs");
+    }
+
+    #[test]
+    fn size_buckets_are_labeled() {
+        let tracer = LeakTracer::<2>::new();
+        for (ptr, size) in [
+            (0x10, 8),
+            (0x20, 64),
+            (0x30, 65),
+            (0x40, 5000),
+            (0x50, 1 << 20),
+        ] {
+            inject(&tracer, fake_record(ptr, size, "site"));
+        }
+
+        assert_eq!(
+            tracer.count_by_size_bucket(),
+            [
+                ("0-64", 2, 72),
+                ("65-256", 1, 65),
+                ("257-1024", 0, 0),
+                ("1025-4096", 0, 0),
+                ("4097-16384", 1, 5000),
+                ("16385-65536", 0, 0),
+                ("65537+", 1, 1 << 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn arming_drops_earlier_records() {
+        let tracer = LeakTracer::<2>::builder().enabled(false).build();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let unseen = unsafe { tracer.alloc(layout) };
+        assert!(tracer.get_leaks().is_empty());
+
+        tracer.enable();
+        let startup = unsafe { tracer.alloc(layout) };
+        tracer.arm();
+        assert!(tracer.get_leaks().is_empty());
+        assert_eq!(tracer.live_bytes(), 0);
+
+        let steady = unsafe { tracer.alloc(layout) };
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks.len(), 1);
+        assert!(leaks.contains_key(&(steady as usize)));
+
+        for ptr in [unseen, startup, steady] {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn allocations_get_the_innermost_label() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let (outer, inner) = {
+            let _request = tracer.push_label("request 42");
+            let outer = unsafe { tracer.alloc(layout) };
+            let inner = {
+                let _decode = tracer.push_label("image decode");
+                unsafe { tracer.alloc(layout) }
+            };
+            (outer, inner)
+        };
+        let unlabeled = unsafe { tracer.alloc(layout) };
+
+        let leaks = tracer.get_leaks();
+        let label = |ptr: *mut u8| leaks[&(ptr as usize)].label.clone();
+        assert_eq!(label(outer).as_deref(), Some("request 42"));
+        assert_eq!(label(inner).as_deref(), Some("image decode"));
+        assert_eq!(label(unlabeled), None);
+        assert!(leaks[&(inner as usize)]
+            .to_string()
+            .contains("label image decode"));
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn leaks_by_label_totals_each_label() {
+        let tracer = LeakTracer::<2>::new();
+        let alloc = |size| unsafe { tracer.alloc(Layout::from_size_align(size, 8).unwrap()) };
+        {
+            let _label = tracer.push_label("requests");
+            alloc(100);
+            alloc(200);
+        }
+        {
+            let _label = tracer.push_label("cache");
+            alloc(4096);
+        }
+        alloc(1);
+
+        let by_label = tracer.leaks_by_label();
+        assert_eq!(by_label.len(), 3);
+        assert_eq!(by_label[&HeaplessString::from("requests")], (2, 300));
+        assert_eq!(by_label[&HeaplessString::from("cache")], (1, 4096));
+        assert_eq!(by_label[&HeaplessString::new()], (1, 1));
+
+        free_all(&tracer);
+    }
+
+    frame_fn! {
+        fn parse_config(tracer: &LeakTracer<8>, layout: Layout) -> *mut u8 {
+            unsafe { tracer.alloc(layout) }
+        }
+    }
+
+    #[test]
+    fn symbol_queries_resolve_lazy_records() {
+        let tracer = LeakTracer::<8>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        parse_config(&tracer, layout);
+
+        assert!(tracer.any_leak_with_symbol("parse_config"));
+        assert_eq!(tracer.count_leaks_with_symbol("parse_config"), 1);
+        assert_eq!(tracer.leak_bytes_for_symbol("parse_config"), 8);
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn symbol_queries_find_live_leaks() {
+        let tracer = LeakTracer::<8>::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let config = parse_config(&tracer, layout);
+        let other = unsafe { tracer.alloc(layout) };
+
+        // Release builds carry no file names, so the stack is made up.
+        tracer.set_backtrace_provider(|| vec![fake_call("load_config")]);
+        let provided = unsafe { tracer.alloc(layout) };
+
+        assert!(tracer.any_leak_with_symbol("parse_config"));
+        assert_eq!(tracer.count_leaks_with_symbol("parse_config"), 1);
+        assert_eq!(tracer.count_leaks_with_symbol("fake.rs"), 1);
+        assert!(!tracer.any_leak_with_symbol("no_such_function"));
+
+        unsafe { tracer.dealloc(config, layout) };
+        assert!(!tracer.any_leak_with_symbol("parse_config"));
+        unsafe { tracer.dealloc(other, layout) };
+        unsafe { tracer.dealloc(provided, layout) };
+    }
+
+    #[test]
+    fn sorted_leaks_follow_allocation_order() {
+        let tracer = LeakTracer::<2>::new();
+        let ptrs: Vec<usize> = (1..=20)
+            .map(|size| unsafe { tracer.alloc(Layout::from_size_align(size, 8).unwrap()) as usize })
+            .collect();
+
+        let sorted = tracer.get_leaks_sorted();
+        let order: Vec<usize> = sorted.iter().map(|record| record.ptr).collect();
+        assert_eq!(order, ptrs);
+        for _ in 0..3 {
+            let again: Vec<usize> = tracer.get_leaks_sorted().iter().map(|r| r.ptr).collect();
+            assert_eq!(again, order);
+        }
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn reallocations_are_counted_on_the_original_record() {
+        let tracer = LeakTracer::<4>::new();
+        let mut layout = Layout::from_size_align(8, 8).unwrap();
+        let mut ptr = unsafe { tracer.alloc(layout) };
+        let seq = tracer.get_leaks()[&(ptr as usize)].seq;
+        for size in [16, 4096, 1 << 16, 32] {
+            ptr = unsafe { tracer.realloc(ptr, layout, size) };
+            layout = Layout::from_size_align(size, 8).unwrap();
+        }
+
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks.len(), 1);
+        let record = &leaks[&(ptr as usize)];
+        assert_eq!(record.realloc_count, 4);
+        assert_eq!(record.seq, seq);
+        assert_eq!(record.size, 32);
+        assert_eq!(tracer.live_bytes(), 32);
+        assert!(record.to_string().contains("reallocated 4 times"));
+
+        unsafe { tracer.dealloc(ptr, layout) };
+        assert!(tracer.get_leaks().is_empty());
+    }
+
+    #[test]
+    fn disable_scopes_nest() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let outer = tracer.disable_scope();
+        let inner = tracer.disable_scope();
+        let a = unsafe { tracer.alloc(layout) };
+        drop(inner);
+        let b = unsafe { tracer.alloc(layout) };
+        drop(outer);
+        let c = unsafe { tracer.alloc(layout) };
+
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks.len(), 1);
+        assert!(leaks.contains_key(&(c as usize)));
+
+        for ptr in [a, b, c] {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        assert!(tracer.get_leaks().is_empty());
+    }
+
+    #[test]
+    fn global_tracer_is_found_by_its_type() {
+        let tracer: &'static LeakTracer<3> = Box::leak(Box::new(LeakTracer::new()));
+        assert!(LeakTracer::<3>::global().is_none());
+
+        assert!(tracer.register_global());
+        assert!(std::ptr::eq(LeakTracer::<3>::global().unwrap(), tracer));
+        assert!(LeakTracer::<4>::global().is_none());
+
+        let other: &'static LeakTracer<3> = Box::leak(Box::new(LeakTracer::new()));
+        assert!(!other.register_global());
+        assert!(std::ptr::eq(LeakTracer::<3>::global().unwrap(), tracer));
+    }
+
+    #[test]
+    fn realloc_growth_and_shrinkage_are_counted_apart() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        let ptr = unsafe { tracer.realloc(ptr, layout, 1000) };
+        let ptr = unsafe { tracer.realloc(ptr, Layout::from_size_align(1000, 8).unwrap(), 40) };
+
+        let stats = tracer.stats();
+        assert_eq!(stats.realloc_grow_bytes, 900);
+        assert_eq!(stats.realloc_shrink_bytes, 960);
+        assert_eq!(stats.live_bytes, 40);
+
+        unsafe { tracer.dealloc(ptr, Layout::from_size_align(40, 8).unwrap()) };
+    }
+
+    #[test]
+    fn alignment_survives_moving_reallocations() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(64, 4096).unwrap();
+        let tracked = unsafe { tracer.alloc(layout) };
+        let untracked = {
+            let _scope = tracer.disable_scope();
+            unsafe { tracer.alloc(layout) }
+        };
+        assert_eq!(tracer.get_leaks()[&(tracked as usize)].align, 4096);
+
+        // Over-aligned blocks are always moved by the system allocator.
+        let tracked_moved = unsafe { tracer.realloc(tracked, layout, 1 << 16) };
+        let untracked_moved = unsafe { tracer.realloc(untracked, layout, 1 << 16) };
+        assert_ne!(tracked_moved, tracked);
+        assert_ne!(untracked_moved, untracked);
+
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks.len(), 2);
+        assert_eq!(leaks[&(tracked_moved as usize)].align, 4096);
+        assert_eq!(leaks[&(untracked_moved as usize)].align, 4096);
+
+        let layout = Layout::from_size_align(1 << 16, 4096).unwrap();
+        unsafe {
+            tracer.dealloc(tracked_moved, layout);
+            tracer.dealloc(untracked_moved, layout);
+        }
+    }
+
+    #[test]
+    fn is_empty_once_everything_is_freed() {
+        let tracer = LeakTracer::<2>::new();
+        assert!(tracer.is_empty());
+        let layout = Layout::from_size_align(0x10, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        assert!(!tracer.is_empty());
+        unsafe { tracer.dealloc(ptr, layout) };
+        assert!(tracer.is_empty());
+    }
+
+    #[test]
+    fn age_histogram_buckets_by_allocation_time() {
+        fn at_two_minutes() -> Duration {
+            Duration::from_secs(120)
+        }
+
+        let tracer = LeakTracer::<2>::new();
+        tracer.set_clock(at_two_minutes);
+        for (ptr, secs) in [
+            (0x10, 119.5),
+            (0x20, 115.0),
+            (0x30, 95.0),
+            (0x40, 0.0),
+            (0x50, 30.0),
+        ] {
+            let mut record = fake_record(ptr, 8, "aged");
+            record.allocated_at = Duration::from_secs_f64(secs);
+            inject(&tracer, record);
+        }
+
+        assert_eq!(
+            tracer.age_histogram(),
+            vec![("<1s", 1), ("1-10s", 1), ("10-60s", 1), (">60s", 2)]
+        );
+    }
+
+    #[test]
+    fn verbose_display_locates_frames_in_their_module() {
+        let tracer = LeakTracer::<8>::new();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = alloc_from_one_site(&tracer, layout);
+        let record = tracer.get_leaks().remove(&(ptr as usize)).unwrap();
+        assert!(record.stack.iter().all(|call| call.ip != 0));
+
+        let plain = record.to_string();
+        let verbose = record
+            .display_with(ReportOptions::new().verbose(true))
+            .to_string();
+        assert_eq!(plain.lines().count(), verbose.lines().count());
+        assert!(!plain.contains("+0x"));
+        if cfg!(unix) {
+            assert!(verbose.lines().skip(1).all(|line| line.contains("+0x")));
+        }
+
+        unsafe { tracer.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn reset_stats_keeps_live_records() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        let ptr = unsafe { tracer.realloc(ptr, layout, 64) };
+        assert_eq!(tracer.stats().realloc_grow_bytes, 32);
+
+        tracer.reset_stats();
+        let stats = tracer.stats();
+        assert_eq!(stats.realloc_grow_bytes, 0);
+        assert_eq!(stats.live_allocations, 1);
+        assert_eq!(stats.live_bytes, 64);
+        assert_eq!(tracer.live_bytes(), 64);
+
+        unsafe { tracer.dealloc(ptr, Layout::from_size_align(64, 8).unwrap()) };
+    }
+
+    #[test]
+    fn capture_depth_limits_the_captured_frames() {
+        let eager = LeakTracer::<16>::builder().capture_depth(3).build();
+        let lazy = LeakTracer::<16>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .capture_depth(3)
+            .build();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let a = unsafe { eager.alloc(layout) };
+        let b = unsafe { lazy.alloc(layout) };
+        assert_eq!(eager.get_leaks()[&(a as usize)].stack.len(), 3);
+        assert_eq!(lazy.inner.allocates.lock()[&(b as usize)].frames.len(), 3);
+
+        eager.set_capture_depth(100);
+        let c = unsafe { eager.alloc(layout) };
+        assert!(eager.get_leaks()[&(c as usize)].stack.len() > 3);
+
+        free_all(&eager);
+        free_all(&lazy);
+    }
+
+    /// Allocates, reallocates and frees from several threads at once, then
+    /// checks the records and totals are consistent.
+    fn exercise(tracer: &'static LeakTracer<4>) {
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let layout = Layout::from_size_align(32, 8).unwrap();
+                    let mut kept = Vec::new();
+                    for i in 0..200 {
+                        let ptr = unsafe { tracer.alloc(layout) };
+                        let ptr = unsafe { tracer.realloc(ptr, layout, 64) };
+                        if i % 10 == 0 {
+                            kept.push(ptr as usize);
+                        } else {
+                            unsafe { tracer.dealloc(ptr, Layout::from_size_align(64, 8).unwrap()) };
+                        }
+                    }
+                    kept
+                })
+            })
+            .collect();
+        let kept: Vec<usize> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks.len(), 80);
+        assert!(kept
+            .iter()
+            .all(|ptr| leaks[ptr].size == 64 && leaks[ptr].realloc_count == 1));
+        assert_eq!(tracer.live_bytes(), 80 * 64);
+        assert_eq!(tracer.stats().realloc_grow_bytes, 4 * 200 * 32);
+        free_all(tracer);
+        assert!(tracer.is_empty());
+    }
+
+    #[test]
+    fn both_lock_kinds_keep_records_consistent() {
+        for kind in [crate::LockKind::Spin, crate::LockKind::Blocking] {
+            exercise(Box::leak(Box::new(
+                LeakTracer::builder().lock_kind(kind).build(),
+            )));
+        }
+    }
+
+    #[test]
+    fn allowed_fingerprints_are_left_out() {
+        let tracer = LeakTracer::<2>::new();
+        inject(&tracer, fake_record(0x10, 8, "known_leak"));
+        inject(&tracer, fake_record(0x20, 8, "known_leak"));
+        inject(&tracer, fake_record(0x30, 8, "new_leak"));
+        let known = fake_record::<2>(0, 0, "known_leak").fingerprint();
+
+        let leaks = tracer.leaks_excluding_fingerprints(&[known]);
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(leaks[0].ptr, 0x30);
+        assert_eq!(tracer.leaks_excluding_fingerprints(&[]).len(), 3);
+    }
+
+    #[test]
+    fn reallocated_blocks_have_realloc_origin() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let fresh = unsafe { tracer.alloc(layout) };
+        let grown = unsafe { tracer.alloc(layout) };
+        let grown = unsafe { tracer.realloc(grown, layout, 1 << 12) };
+        let untracked = {
+            let _scope = tracer.disable_scope();
+            unsafe { tracer.alloc(layout) }
+        };
+        let moved = unsafe { tracer.realloc(untracked, layout, 1 << 12) };
+
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks[&(fresh as usize)].origin, AllocOrigin::Alloc);
+        assert_eq!(leaks[&(grown as usize)].origin, AllocOrigin::Realloc);
+        if moved != untracked {
+            assert_eq!(leaks[&(moved as usize)].origin, AllocOrigin::Realloc);
+            assert!(leaks[&(moved as usize)]
+                .to_string()
+                .contains("from realloc"));
+        }
+        let reallocated = tracer.leaks_with_origin(AllocOrigin::Realloc);
+        assert!(reallocated
+            .iter()
+            .all(|record| record.ptr != fresh as usize));
+        assert!(reallocated
+            .iter()
+            .any(|record| record.ptr == grown as usize));
+
+        free_all(&tracer);
+        if moved == untracked {
+            unsafe { tracer.dealloc(moved, Layout::from_size_align(1 << 12, 8).unwrap()) };
+        }
+    }
+
+    #[test]
+    fn size_band_query_is_inclusive() {
+        let tracer = LeakTracer::<2>::new();
+        for (ptr, size) in [
+            (0x10, 4095),
+            (0x20, 4096),
+            (0x30, 6000),
+            (0x40, 8192),
+            (0x50, 8193),
+        ] {
+            inject(&tracer, fake_record(ptr, size, "sized"));
+        }
+
+        let mut ptrs: Vec<_> = tracer
+            .leaks_between_sizes(4096, 8192)
+            .iter()
+            .map(|record| record.ptr)
+            .collect();
+        ptrs.sort_unstable();
+        assert_eq!(ptrs, [0x20, 0x30, 0x40]);
+        assert!(tracer.leaks_between_sizes(1, 100).is_empty());
+    }
+
+    #[test]
+    fn peak_live_allocations_is_the_high_water_mark() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptrs: Vec<_> = (0..5).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        for &ptr in &ptrs[..4] {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        let more: Vec<_> = (0..2).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        assert_eq!(tracer.peak_live_allocations(), 5);
+
+        tracer.reset_peak_live_allocations();
+        assert_eq!(tracer.peak_live_allocations(), 3);
+
+        unsafe { tracer.dealloc(ptrs[4], layout) };
+        for ptr in more {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        assert_eq!(tracer.peak_live_allocations(), 3);
+    }
+
+    #[test]
+    fn call_matches_name_or_filename() {
+        let call = fake_call("parser::parse_config");
+        assert!(call.matches("parse_config"));
+        assert!(call.matches("fake.rs"));
+        assert!(call.matches(""));
+        assert!(!call.matches("serializer"));
+
+        let unknown = Call {
+            name: None,
+            filename: None,
+            ..fake_call("x")
+        };
+        assert!(!unknown.matches(""));
+    }
+
+    #[test]
+    fn recursion_collapses_into_a_multiplier() {
+        let mut record = fake_record::<16>(0x10, 8, "alloc_node");
+        for _ in 0..12 {
+            record.stack.push(fake_call("recurse")).unwrap();
+        }
+        record.stack.push(fake_call("main")).unwrap();
+
+        let collapsed = record
+            .display_with(ReportOptions::new().collapse_recursion(true))
+            .to_string();
+        let lines: Vec<_> = collapsed.lines().skip(1).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("  recurse @ "));
+        assert!(lines[1].ends_with(" (x12)"));
+        assert!(!lines[2].contains("(x"));
+        assert_eq!(record.to_string().lines().count(), 15);
+    }
+
+    #[test]
+    fn eager_records_keep_their_frame_addresses() {
+        let tracer = LeakTracer::<8>::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = alloc_from_one_site(&tracer, layout);
+        let record = tracer.get_leaks().remove(&(ptr as usize)).unwrap();
+
+        assert!(!record.frames.is_empty());
+        assert!(record.frames.iter().all(|&ip| ip != 0));
+        // Calls follow the frames they were resolved from, in order.
+        let positions: Vec<usize> = record
+            .stack
+            .iter()
+            .map(|call| record.frames.iter().position(|&ip| ip == call.ip).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        unsafe { tracer.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn alloc_and_free_calls_are_counted() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptrs: Vec<_> = (0..7).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        for &ptr in &ptrs[..5] {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        tracer.disable();
+        unsafe { tracer.dealloc(ptrs[5], layout) };
+        tracer.enable();
+        assert_eq!((tracer.alloc_count(), tracer.free_count()), (7, 5));
+
+        tracer.reset_stats();
+        unsafe { tracer.dealloc(ptrs[6], layout) };
+        assert_eq!((tracer.alloc_count(), tracer.free_count()), (0, 1));
+    }
+
+    #[test]
+    fn sites_sharing_a_top_frame_aggregate() {
+        let tracer = LeakTracer::<3>::new();
+        let shim = "__rust_alloc";
+        let mut first = fake_record(0x10, 100, shim);
+        first.stack.push(fake_call("Vec::push")).unwrap();
+        first.stack.push(fake_call("load_users")).unwrap();
+        let mut second = fake_record(0x20, 20, shim);
+        second.stack.push(fake_call("Vec::push")).unwrap();
+        second.stack.push(fake_call("load_groups")).unwrap();
+        inject(&tracer, first);
+        inject(&tracer, second);
+        inject(&tracer, fake_record(0x30, 5, "String::from"));
+
+        let by_frame = tracer.leaks_by_top_frame();
+        assert_eq!(by_frame.len(), 2);
+        assert_eq!(by_frame[&HeaplessString::from("Vec::push")], (2, 120));
+        assert_eq!(by_frame[&HeaplessString::from("String::from")], (1, 5));
+    }
+
+    #[test]
+    fn draining_stats_loses_no_counts() {
+        let tracer: &'static LeakTracer<2> = Box::leak(Box::new(LeakTracer::new()));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let layout = Layout::from_size_align(16, 8).unwrap();
+                    for _ in 0..500 {
+                        let ptr = unsafe { tracer.alloc(layout) };
+                        let ptr = unsafe { tracer.realloc(ptr, layout, 32) };
+                        unsafe { tracer.dealloc(ptr, Layout::from_size_align(32, 8).unwrap()) };
+                    }
+                })
+            })
+            .collect();
+
+        let mut total = crate::Stats::default();
+        let mut add = |stats: crate::Stats| {
+            total.alloc_count += stats.alloc_count;
+            total.free_count += stats.free_count;
+            total.realloc_grow_bytes += stats.realloc_grow_bytes;
+        };
+        while !workers.iter().all(|worker| worker.is_finished()) {
+            add(tracer.drain_stats());
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        unsafe { tracer.dealloc(tracer.alloc(layout), layout) };
+        assert!(tracer.avg_lifetime().is_some());
+        add(tracer.drain_stats());
+        // The lifetimes went with the drained interval.
+        assert_eq!(tracer.avg_lifetime(), None);
+
+        assert_eq!(total.alloc_count, 2001);
+        assert_eq!(total.free_count, 2001);
+        assert_eq!(total.realloc_grow_bytes, 2000 * 16);
+        assert_eq!(tracer.drain_stats().alloc_count, 0);
+    }
+
+    #[test]
+    fn capture_failure_policy_applies_to_empty_stacks() {
+        use crate::{CaptureFailure, UNWIND_FAILED};
+
+        // Capturing no frame at all stands in for a failed unwind.
+        let record = LeakTracer::<4>::builder()
+            .capture_depth(0)
+            .on_capture_failure(CaptureFailure::Record)
+            .build();
+        let skip = LeakTracer::<4>::builder()
+            .capture_depth(0)
+            .on_capture_failure(CaptureFailure::Skip)
+            .build();
+        let sentinel = LeakTracer::<4>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .capture_depth(0)
+            .on_capture_failure(CaptureFailure::Sentinel)
+            .build();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptrs = [&record, &skip, &sentinel].map(|tracer| unsafe { tracer.alloc(layout) });
+
+        assert!(record.get_leaks()[&(ptrs[0] as usize)].stack.is_empty());
+        assert!(skip.is_empty());
+        let leaks = sentinel.get_leaks();
+        let stack = &leaks[&(ptrs[2] as usize)].stack;
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack[0].name.as_deref(), Some(UNWIND_FAILED));
+
+        unsafe { skip.dealloc(ptrs[1], layout) };
+        free_all(&record);
+        free_all(&sentinel);
+    }
+
+    #[test]
+    fn would_track_applies_the_size_filter() {
+        let tracer = LeakTracer::<2>::builder().min_size(64).build();
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let large = Layout::from_size_align(64, 8).unwrap();
+        assert!(!tracer.would_track(&small));
+        assert!(tracer.would_track(&large));
+        {
+            let _scope = tracer.disable_scope();
+            assert!(!tracer.would_track(&large));
+        }
+
+        let a = unsafe { tracer.alloc(small) };
+        let b = unsafe { tracer.alloc(large) };
+        let leaks = tracer.get_leaks();
+        assert_eq!(leaks.len(), 1);
+        assert!(leaks.contains_key(&(b as usize)));
+
+        unsafe { tracer.dealloc(a, small) };
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn would_track_agrees_with_alloc() {
+        use crate::OverBudget;
+
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let agrees = |tracer: &LeakTracer<2>| {
+            let expected = tracer.would_track(&layout);
+            let before = tracer.stats().live_allocations;
+            let ptr = unsafe { tracer.alloc(layout) };
+            assert_eq!(tracer.stats().live_allocations > before, expected);
+            ptr
+        };
+
+        let budgeted = LeakTracer::<2>::builder()
+            .capture_budget(1, OverBudget::Skip)
+            .build();
+        let first = agrees(&budgeted);
+        assert!(!budgeted.would_track(&layout));
+        let second = agrees(&budgeted);
+        unsafe { budgeted.dealloc(second, layout) };
+        unsafe { budgeted.dealloc(first, layout) };
+
+        let capped = LeakTracer::<2>::new();
+        capped.set_max_call_sites(1);
+        let first = agrees(&capped);
+        assert!(!capped.would_track(&layout));
+        unsafe { capped.dealloc(first, layout) };
+
+        let detached = LeakTracer::<2>::new();
+        detached.detach();
+        assert!(!detached.would_track(&layout));
+        let ptr = agrees(&detached);
+        unsafe { detached.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn only_the_first_allocations_within_budget_get_stacks() {
+        use crate::OverBudget;
+
+        let recorded = LeakTracer::<4>::builder()
+            .capture_budget(5, OverBudget::EmptyStack)
+            .build();
+        let skipped = LeakTracer::<4>::builder()
+            .capture_budget(5, OverBudget::Skip)
+            .build();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        for tracer in [&recorded, &skipped] {
+            for _ in 0..8 {
+                unsafe { tracer.alloc(layout) };
+            }
+        }
+
+        let records = recorded.get_leaks_sorted();
+        assert_eq!(records.len(), 8);
+        assert!(records[..5].iter().all(|record| !record.stack.is_empty()));
+        assert!(records[5..].iter().all(|record| record.stack.is_empty()));
+        assert_eq!(skipped.get_leaks().len(), 5);
+
+        free_all(&recorded);
+        free_all(&skipped);
+    }
+
+    #[test]
+    fn map_capacity_grows_with_the_records() {
+        let tracer = LeakTracer::<4>::new();
+        assert_eq!(tracer.map_len(), 0);
+
+        inject(&tracer, fake_record(0x1000, 8, "first"));
+        let initial = tracer.map_capacity();
+        assert!(initial >= tracer.map_len());
+
+        for i in 1..=initial {
+            inject(&tracer, fake_record(0x1000 + i * 16, 8, "more"));
+        }
+        assert_eq!(tracer.map_len(), initial + 1);
+        assert!(tracer.map_capacity() > initial);
+        assert!(tracer.map_capacity() >= tracer.map_len());
+    }
+
+    #[test]
+    fn frees_keep_records_in_allocation_profile_mode() {
+        let tracer = LeakTracer::<4>::builder().allocation_profile(true).build();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        unsafe { tracer.dealloc(ptr, layout) };
+
+        let records = tracer.get_leaks();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[&(ptr as usize)].size, 24);
+        assert_eq!(tracer.free_count(), 1);
+    }
+
+    #[test]
+    fn allocation_profile_keeps_the_latest_records_and_counts_every_site() {
+        let tracer = LeakTracer::<4>::builder()
+            .allocation_profile(true)
+            .max_profile_records(4)
+            .build();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        // Loops of unknown length aren't unrolled, which would make each
+        // unrolled call a site of its own.
+        let mut ptrs: Vec<_> = (0..std::hint::black_box(10))
+            .map(|_| alloc_from_one_site(&tracer, layout))
+            .collect();
+        ptrs.extend((0..std::hint::black_box(2)).map(|_| allocate_widget(&tracer, 24)));
+
+        let records = tracer.get_leaks();
+        assert_eq!(records.len(), 4);
+        assert!(ptrs[8..]
+            .iter()
+            .all(|ptr| records.contains_key(&(*ptr as usize))));
+        let site = records[&(ptrs[8] as usize)].stack_key;
+        let widget = records[&(ptrs[10] as usize)].stack_key;
+        let sites = tracer.profile_sites();
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites[&site], (10, 240));
+        assert_eq!(sites[&widget], (2, 48));
+
+        for ptr in ptrs {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+        assert_eq!(tracer.get_leaks().len(), 4);
+        tracer.clear();
+    }
+
+    #[test]
+    fn stripped_reports_are_identical_across_runs() {
+        let options = ReportOptions::new().verbose(true).strip_addresses(true);
+        let reports: Vec<String> = [ResolveMode::Eager, ResolveMode::Lazy]
+            .iter()
+            .flat_map(|mode| {
+                [0, 1].iter().map(move |_| {
+                    let tracer = LeakTracer::<8>::builder().resolve_mode(*mode).build();
+                    for size in [16, 32] {
+                        alloc_from_one_site(&tracer, Layout::from_size_align(size, 8).unwrap());
+                    }
+                    let report = tracer.display_leaks_with(options).to_string();
+                    free_all(&tracer);
+                    report
+                })
+            })
+            .collect();
+
+        assert_eq!(reports[0], reports[1]);
+        assert_eq!(reports[2], reports[3]);
+        assert!(!reports[0].contains("0x"));
+        assert!(reports[2].contains("[unresolved]"));
+        assert!(!reports[2].contains("0x"));
+    }
+
+    #[test]
+    fn resolve_all_reports_progress() {
+        let tracer = LeakTracer::<4>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        for _ in 0..70 {
+            unsafe { tracer.alloc(layout) };
+        }
+
+        let mut calls = Vec::new();
+        tracer.resolve_all(|done, total| calls.push((done, total)));
+        assert_eq!(calls, [(32, 70), (64, 70), (70, 70)]);
+        let records = tracer.get_leaks_sorted();
+        assert!(records.iter().all(|record| record.symbolized));
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn only_armed_threads_are_recorded() {
+        let tracer = LeakTracer::<4>::builder().armed_threads_only(true).build();
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let (armed, unarmed) = std::thread::scope(|scope| {
+            let armed = scope.spawn(|| {
+                let _armed = tracer.arm_current_thread();
+                unsafe { tracer.alloc(layout) as usize }
+            });
+            let unarmed = scope.spawn(|| unsafe { tracer.alloc(layout) as usize });
+            (armed.join().unwrap(), unarmed.join().unwrap())
+        });
+
+        let records = tracer.get_leaks();
+        assert_eq!(records.len(), 1);
+        assert!(records.contains_key(&armed));
+        assert!(!tracer.would_track(&layout));
+
+        free_all(&tracer);
+        unsafe { tracer.dealloc(unarmed as *mut u8, layout) };
+    }
+
+    frame_fn! {
+        fn allocate_widget(tracer: &impl GlobalAlloc, size: usize) -> *mut u8 {
+            unsafe { tracer.alloc(Layout::from_size_align(size, 8).unwrap()) }
+        }
+    }
+
+    #[test]
+    fn leak_bytes_are_summed_per_symbol() {
+        let tracer = LeakTracer::<16>::new();
+        for size in [100, 200, 300] {
+            allocate_widget(&tracer, size);
+        }
+        unsafe { tracer.alloc(Layout::from_size_align(4000, 8).unwrap()) };
+
+        assert_eq!(tracer.leak_bytes_for_symbol("allocate_widget"), 600);
+        assert_eq!(tracer.leak_bytes_for_symbol("no_such_function"), 0);
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn state_snapshot_is_consistent() {
+        let tracer = LeakTracer::<4>::new();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let ptrs: Vec<_> = (0..5).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        unsafe { tracer.dealloc(ptrs[0], layout) };
+
+        let snapshot = tracer.snapshot_state();
+        assert_eq!(snapshot.records.len(), snapshot.stats.live_allocations);
+        assert_eq!(snapshot.stats.live_allocations, 4);
+        assert_eq!(snapshot.stats.live_bytes, 4 * 48);
+        assert_eq!(snapshot.stats.alloc_count, 5);
+        assert_eq!(snapshot.stats.free_count, 1);
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn registered_symbols_name_opaque_frames() {
+        let tracer = LeakTracer::<4>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        // Nothing is mapped there, so symbolization can't name it.
+        let opaque = 0x10_0000;
+        let mut record = fake_record::<4>(0x10, 8, "unused");
+        record.stack.clear();
+        record.frames.push(opaque + 0x24).unwrap();
+        record.symbolized = false;
+        inject(&tracer, record);
+
+        tracer.register_symbol(opaque, "stripped::handler");
+        let records = tracer.get_leaks();
+        let call = &records[&0x10].stack[0];
+        assert_eq!(call.name.as_deref(), Some("stripped::handler"));
+        assert_eq!(call.addr, opaque);
+        assert_eq!(call.ip, opaque + 0x24);
+    }
+
+    #[test]
+    fn bytes_by_size_class_follow_allocations() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let a = unsafe { tracer.alloc(layout(24)) };
+        let b = unsafe { tracer.alloc(layout(32)) };
+        let c = unsafe { tracer.alloc(layout(1000)) };
+        assert_eq!(tracer.bytes_by_size_class(), [(32, 56), (1024, 1000)]);
+
+        unsafe { tracer.dealloc(b, layout(32)) };
+        let a = unsafe { tracer.realloc(a, layout(24), 100) };
+        assert_eq!(tracer.bytes_by_size_class(), [(128, 100), (1024, 1000)]);
+        assert_eq!(crate::size_class(usize::MAX), 64);
+        assert_eq!(crate::class_size(64), usize::MAX);
+
+        unsafe { tracer.dealloc(a, layout(100)) };
+        unsafe { tracer.dealloc(c, layout(1000)) };
+        assert!(tracer.bytes_by_size_class().is_empty());
+    }
+
+    #[test]
+    fn profile_frees_leave_the_records_alone() {
+        let tracer = LeakTracer::<2>::builder().allocation_profile(true).build();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+
+        // Anything touching the records would wait for this lock forever.
+        let records = tracer.inner.allocates.lock();
+        unsafe { tracer.dealloc(ptr, layout) };
+        drop(records);
+
+        assert_eq!(tracer.free_count(), 1);
+        assert!(tracer.get_leaks().contains_key(&(ptr as usize)));
+        tracer.clear();
+    }
+
+    #[test]
+    fn detached_tracers_only_forward() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let before = unsafe { tracer.alloc(layout) };
+        tracer.detach();
+
+        // Anything touching the records would wait for this lock forever.
+        let records = tracer.inner.allocates.lock();
+        let after = unsafe { tracer.alloc(layout) };
+        let after = unsafe { tracer.realloc(after, layout, 64) };
+        unsafe { tracer.dealloc(after, Layout::from_size_align(64, 8).unwrap()) };
+        unsafe { tracer.dealloc(before, layout) };
+        drop(records);
+
+        assert_eq!(tracer.alloc_count(), 1);
+        assert_eq!(tracer.free_count(), 0);
+        assert!(tracer.get_leaks().contains_key(&(before as usize)));
+    }
+
+    #[test]
+    fn leaks_are_found_by_file() {
+        let tracer = LeakTracer::<16>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        // Release builds carry no file names, so the stack is made up.
+        tracer.set_backtrace_provider(|| vec![fake_call("allocate_widget")]);
+        let ptr = allocate_widget(&tracer, 72);
+
+        let found = tracer.leaks_matching_file("src/fake.rs");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].ptr, ptr as usize);
+        assert!(tracer.leaks_matching_file("no_such_file.rs").is_empty());
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn module_offset_frames_are_not_symbolized() {
+        let tracer = LeakTracer::<8>::builder()
+            .resolve_mode(ResolveMode::ModuleOffset)
+            .build();
+        let ptr = allocate_widget(&tracer, 40);
+
+        let record = tracer.get_leaks().remove(&(ptr as usize)).unwrap();
+        assert!(record.symbolized);
+        assert_eq!(record.stack.len(), record.frames.len());
+        assert!(!record.stack.is_empty());
+        let modules = tracer.modules();
+        for (call, ip) in record.stack.iter().zip(record.frames.iter()) {
+            assert_eq!(call.name, None);
+            assert_eq!(call.line, None);
+            assert_eq!(call.ip, *ip);
+            let module = call.module.expect("frame outside any module");
+            assert!(module < modules.len());
+            assert!(call.offset > 0 && call.offset <= *ip);
+        }
+        assert!(record.to_string().contains("[module 0]+0x"));
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn backtrace_provider_replaces_unwinding() {
+        let tracer = LeakTracer::<4>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let calls = vec![
+            Call {
+                ip: 0x1234,
+                ..fake_call("inner")
+            },
+            Call {
+                ip: 0x5678,
+                ..fake_call("outer")
+            },
+        ];
+        let provided = calls.clone();
+        tracer.set_backtrace_provider(move || provided.clone());
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+
+        let record = tracer.get_leaks().remove(&(ptr as usize)).unwrap();
+        assert!(record.symbolized);
+        assert_eq!(record.stack.as_slice(), calls.as_slice());
+        assert_eq!(record.frames.as_slice(), [0x1234, 0x5678]);
+
+        tracer.clear_backtrace_provider();
+        let unwound = unsafe { tracer.alloc(layout) };
+        assert_ne!(tracer.get_leaks()[&(unwound as usize)].stack[0], calls[0]);
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn bytes_allocated_since_count_what_was_kept() {
+        let tracer = LeakTracer::<2>::new();
+        let before = unsafe { tracer.alloc(Layout::from_size_align(1000, 8).unwrap()) };
+        let marker = tracer.mark();
+        let kept = unsafe { tracer.alloc(Layout::from_size_align(300, 8).unwrap()) };
+        let freed = unsafe { tracer.alloc(Layout::from_size_align(100, 8).unwrap()) };
+        unsafe { tracer.dealloc(freed, Layout::from_size_align(100, 8).unwrap()) };
+
+        assert_eq!(tracer.bytes_allocated_since(&marker), 300);
+        assert_eq!(tracer.bytes_allocated_since(&tracer.mark()), 0);
+
+        unsafe { tracer.dealloc(before, Layout::from_size_align(1000, 8).unwrap()) };
+        unsafe { tracer.dealloc(kept, Layout::from_size_align(300, 8).unwrap()) };
+    }
+
+    #[test]
+    fn tracking_resumes_after_a_disabled_closure() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let untracked = tracer.with_tracking_disabled(|| unsafe { tracer.alloc(layout) });
+        assert!(tracer.is_empty());
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tracer.with_tracking_disabled(|| panic!("inside the closure"));
+        }));
+        assert!(panicked.is_err());
+        assert!(tracer.would_track(&layout));
+        let tracked = unsafe { tracer.alloc(layout) };
+        assert!(tracer.get_leaks().contains_key(&(tracked as usize)));
+
+        unsafe { tracer.dealloc(untracked, layout) };
+        unsafe { tracer.dealloc(tracked, layout) };
+    }
+
+    #[test]
+    fn leaks_are_ranked_by_size_and_age() {
+        fn at_100_seconds() -> Duration {
+            Duration::from_secs(100)
+        }
+
+        let tracer = LeakTracer::<2>::new();
+        tracer.set_clock(at_100_seconds);
+        // (ptr, size, allocated at)
+        for (ptr, size, at) in [
+            (0x10, 4000, 99),
+            (0x20, 1000, 0),
+            (0x30, 100, 50),
+            (0x40, 50, 90),
+        ] {
+            let mut record = fake_record(ptr, size, "scored");
+            record.allocated_at = Duration::from_secs(at);
+            inject(&tracer, record);
+        }
+
+        let ranked = |weight| -> Vec<usize> {
+            tracer
+                .leaks_by_score(weight)
+                .iter()
+                .map(|record| record.ptr)
+                .collect()
+        };
+        assert_eq!(ranked(0.0), [0x10, 0x20, 0x30, 0x40]);
+        // Byte-seconds: 4000, 100000, 5000, 500.
+        assert_eq!(ranked(1.0), [0x20, 0x30, 0x10, 0x40]);
+    }
+
+    #[test]
+    fn drained_records_load_into_another_tracer() {
+        let live = LeakTracer::<2>::new();
+        for (ptr, size) in [(0x10, 8), (0x20, 64), (0x30, 5000)] {
+            inject(&live, fake_record(ptr, size, "saved"));
+        }
+        let snapshot = live.drain_leaks();
+
+        let analyzer = LeakTracer::<2>::new();
+        analyzer.load_into(snapshot.into());
+        assert_eq!(
+            analyzer.leaks_by_size_class(),
+            [(8, 1, 8), (64, 1, 64), (8192, 1, 5000)]
+        );
+        assert_eq!(analyzer.live_bytes(), 5072);
+        assert_eq!(analyzer.largest_leak().unwrap().ptr, 0x30);
+    }
+
+    #[test]
+    fn sites_over_the_cap_fold_into_other() {
+        let tracer = LeakTracer::<2>::new();
+        let site = Arc::new(AtomicUsize::new(0));
+        let provider_site = site.clone();
+        tracer.set_backtrace_provider(move || {
+            let name = ["first", "second", "third"][provider_site.load(Ordering::SeqCst)];
+            vec![fake_call(name)]
+        });
+        tracer.set_max_call_sites(2);
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        for idx in [0, 1, 2, 2, 0] {
+            site.store(idx, Ordering::SeqCst);
+            unsafe { tracer.alloc(layout) };
+        }
+
+        let records = tracer.get_leaks();
+        assert_eq!(records.len(), 3);
+        assert!(records
+            .values()
+            .all(|record| !record.stack[0].matches("third")));
+        assert_eq!(tracer.other_call_sites(), (2, 32));
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn pointers_are_live_until_freed() {
+        let tracer = LeakTracer::<2>::new();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        assert!(tracer.is_live(ptr as usize));
+        assert!(!tracer.is_live(ptr as usize + 1));
+
+        unsafe { tracer.dealloc(ptr, layout) };
+        assert!(!tracer.is_live(ptr as usize));
+    }
+
+    #[test]
+    fn stats_follow_reallocs_without_records() {
+        let tracer = LeakTracer::<2>::builder().store_records(false).build();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        assert_eq!(tracer.live_bytes(), 64);
+
+        // Shrinking usually happens in place, growing this much moves.
+        let ptr = unsafe { tracer.realloc(ptr, layout, 32) };
+        assert_eq!(tracer.live_bytes(), 32);
+        let ptr = unsafe { tracer.realloc(ptr, Layout::from_size_align(32, 8).unwrap(), 1 << 20) };
+        assert_eq!(tracer.live_bytes(), 1 << 20);
+        assert!(tracer.is_empty());
+        let stats = tracer.stats();
+        assert_eq!(stats.live_allocations, 1);
+        assert_eq!(stats.live_bytes, 1 << 20);
+        assert_eq!(stats.realloc_grow_bytes, (1 << 20) - 32);
+        assert_eq!(stats.realloc_shrink_bytes, 32);
+
+        unsafe { tracer.dealloc(ptr, Layout::from_size_align(1 << 20, 8).unwrap()) };
+        assert_eq!(tracer.live_bytes(), 0);
+        assert_eq!(tracer.stats().live_allocations, 0);
+    }
+
+    #[test]
+    fn fingerprint_hex_is_stable_per_site() {
+        let first = fake_record::<2>(0x10, 8, "site");
+        let second = fake_record::<2>(0x20, 64, "site");
+        let hex = first.fingerprint_hex();
+        assert_eq!(hex.len(), 16);
+        assert_eq!(hex, second.fingerprint_hex());
+        assert_eq!(u64::from_str_radix(&hex, 16), Ok(first.fingerprint()));
+        assert_ne!(
+            hex,
+            fake_record::<2>(0x10, 8, "elsewhere").fingerprint_hex()
+        );
+    }
+
+    #[test]
+    fn frames_in_excluded_ranges_are_dropped() {
+        let tracer = LeakTracer::<64>::new();
+        // Deep enough for the whole stack, so nothing new comes up from below.
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let site = |tracer: &LeakTracer<64>| {
+            let ptr = alloc_from_one_site(tracer, layout);
+            tracer.get_leaks().remove(&(ptr as usize)).unwrap()
+        };
+        let before = site(&tracer);
+        let ip = before
+            .stack
+            .iter()
+            .find(|call| call.matches("alloc_from_one_site"))
+            .map(|call| call.ip)
+            .unwrap();
+
+        tracer.add_excluded_range(ip, ip + 1);
+        let after = site(&tracer);
+        assert!(!after.frames.contains(&ip));
+        assert!(!after.stack.iter().any(|call| call.ip == ip));
+        assert_eq!(after.frames.len() + 1, before.frames.len());
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn leaks_iter_borrows_the_records() {
+        let tracer = LeakTracer::<8>::new();
+        inject(&tracer, fake_record(0x1000, 16, "small"));
+        inject(&tracer, fake_record(0x2000, 64, "big"));
+        inject(&tracer, fake_record(0x3000, 128, "big"));
+
+        let leaks = tracer.leaks_iter();
+        assert_eq!(leaks.len(), 3);
+        let mut big = 0;
+        for (ptr, record) in &leaks {
+            assert_eq!(*ptr, record.ptr);
+            if record.size >= 64 {
+                big += 1;
+            }
+        }
+        assert_eq!(big, 2);
+        assert_eq!(
+            leaks.iter().map(|(_, record)| record.size).sum::<usize>(),
+            208
+        );
+        drop(leaks);
+
+        tracer.clear();
+        assert!(tracer.leaks_iter().is_empty());
+    }
+
+    #[test]
+    fn stack_groups_match_a_regroup() {
+        let tracer = LeakTracer::<8>::builder().group_by_stack(true).build();
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let mut ptrs: Vec<_> = (0..10)
+            .map(|_| alloc_from_one_site(&tracer, small))
+            .collect();
+        ptrs.extend((0..4).map(|_| allocate_widget(&tracer, 256)));
+        for ptr in ptrs.drain(..3) {
+            unsafe { tracer.dealloc(ptr, small) };
+        }
+        let grown = unsafe { tracer.realloc(ptrs[0], small, 48) };
+        ptrs[0] = grown;
+
+        let mut regroup = std::collections::HashMap::new();
+        for record in tracer.get_leaks().values() {
+            let group = regroup.entry(record.stack_key).or_insert((0, 0));
+            group.0 += 1;
+            group.1 += record.size;
+        }
+        let groups = tracer.leaks_grouped_by_stack();
+        assert_eq!(groups.len(), regroup.len());
+        for (key, group) in regroup {
+            assert_eq!(groups[&key], group);
+        }
+        assert_eq!(groups.values().map(|group| group.0).sum::<usize>(), 11);
+        assert_eq!(
+            groups.values().map(|group| group.1).sum::<usize>(),
+            48 + 6 * 16 + 4 * 256
+        );
+
+        free_all(&tracer);
+        assert!(tracer.leaks_grouped_by_stack().is_empty());
+    }
+
+    #[test]
+    fn names_only_drops_source_locations() {
+        for mode in [ResolveMode::Eager, ResolveMode::Lazy].iter() {
+            let tracer = LeakTracer::<16>::builder()
+                .resolve_mode(*mode)
+                .names_only(true)
+                .build();
+            let layout = Layout::from_size_align(8, 8).unwrap();
+            let ptr = alloc_from_one_site(&tracer, layout);
+            let record = tracer.get_leaks().remove(&(ptr as usize)).unwrap();
+            assert!(record
+                .stack
+                .iter()
+                .any(|call| call.matches("alloc_from_one_site")));
+            assert!(record
+                .stack
+                .iter()
+                .all(|call| call.filename.is_none() && call.line.is_none() && call.col.is_none()));
+            free_all(&tracer);
+        }
+    }
+
+    #[test]
+    fn pruning_drops_symbols_of_freed_frames() {
+        let tracer = LeakTracer::<16>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let kept = alloc_from_one_site(&tracer, Layout::from_size_align(8, 8).unwrap());
+        let freed = allocate_widget(&tracer, 32);
+        tracer.resolve();
+        let populated = tracer.symbol_cache_len();
+        assert!(populated > 0);
+
+        unsafe { tracer.dealloc(freed, Layout::from_size_align(32, 8).unwrap()) };
+        tracer.prune_symbol_cache();
+        let pruned = tracer.symbol_cache_len();
+        assert!(pruned < populated);
+        assert!(pruned > 0);
+
+        unsafe { tracer.dealloc(kept, Layout::from_size_align(8, 8).unwrap()) };
+        tracer.prune_symbol_cache();
+        assert_eq!(tracer.symbol_cache_len(), 0);
+    }
+
+    #[test]
+    fn event_log_keeps_the_last_events() {
+        let tracer = LeakTracer::<4>::builder().event_log(3).build();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptrs: Vec<_> = (0..3).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        let grown = unsafe { tracer.realloc(ptrs[0], layout, 64) };
+        unsafe { tracer.dealloc(ptrs[1], layout) };
+
+        let events = tracer.recent_events();
+        assert_eq!(
+            events.iter().map(|logged| logged.seq).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        assert_eq!(
+            events.iter().map(|logged| logged.event).collect::<Vec<_>>(),
+            [
+                AllocEvent::Alloc {
+                    ptr: ptrs[2] as usize,
+                    size: 16
+                },
+                AllocEvent::Realloc {
+                    old_ptr: ptrs[0] as usize,
+                    new_ptr: grown as usize,
+                    old_size: 16,
+                    new_size: 64
+                },
+                AllocEvent::Free {
+                    ptr: ptrs[1] as usize,
+                    size: 16
+                },
+            ]
+        );
+
+        unsafe {
+            tracer.dealloc(grown, Layout::from_size_align(64, 8).unwrap());
+            tracer.dealloc(ptrs[2], layout);
+        }
+        assert!(LeakTracer::<4>::new().recent_events().is_empty());
+    }
+
+    #[test]
+    fn set_enabled_returns_the_previous_state() {
+        let tracer = LeakTracer::<2>::new();
+        assert!(tracer.set_enabled(false));
+        assert!(!tracer.set_enabled(false));
+        assert!(!tracer.set_enabled(true));
+        assert!(tracer.set_enabled(true));
+
+        tracer.disable();
+        let was = tracer.set_enabled(true);
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        assert!(tracer.is_live(ptr as usize));
+        tracer.set_enabled(was);
+        let untracked = unsafe { tracer.alloc(layout) };
+        assert!(!tracer.is_live(untracked as usize));
+
+        unsafe {
+            tracer.dealloc(untracked, layout);
+            tracer.enable();
+            tracer.dealloc(ptr, layout);
+        }
+        assert!(tracer.is_empty());
+    }
+
+    frame_fn! {
+        fn grow_widget(tracer: &impl GlobalAlloc, ptr: *mut u8, size: usize) -> *mut u8 {
+            unsafe { tracer.realloc(ptr, Layout::from_size_align(size, 8).unwrap(), 1 << 20) }
+        }
+    }
+
+    #[test]
+    fn moves_keep_the_last_realloc_stack() {
+        let tracer = LeakTracer::<16>::builder().last_realloc_stack(true).build();
+        let ptr = allocate_widget(&tracer, 16);
+        let moved = grow_widget(&tracer, ptr, 16);
+        assert_ne!(moved, ptr);
+
+        let record = tracer.get_leaks().remove(&(moved as usize)).unwrap();
+        let names = |stack: &[Call], needle: &str| stack.iter().any(|call| call.matches(needle));
+        let last = record.last_realloc_stack.as_deref().unwrap();
+        assert!(names(&record.stack, "allocate_widget"));
+        assert!(!names(&record.stack, "grow_widget"));
+        assert!(names(last, "grow_widget"));
+        assert!(!names(last, "allocate_widget"));
+
+        let verbose = record
+            .display_with(ReportOptions::new().verbose(true))
+            .to_string();
+        assert!(verbose.contains("last moved by realloc at:"));
+        assert!(!record.to_string().contains("last moved"));
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn avg_lifetime_is_the_mean_of_freed_blocks() {
+        static NOW_MS: AtomicU64 = AtomicU64::new(0);
+        fn fake_clock() -> Duration {
+            Duration::from_millis(NOW_MS.load(Ordering::SeqCst))
+        }
+
+        let tracer = LeakTracer::<2>::new();
+        tracer.set_clock(fake_clock);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let first = unsafe { tracer.alloc(layout) };
+        NOW_MS.store(10, Ordering::SeqCst);
+        let second = unsafe { tracer.alloc(layout) };
+        let kept = unsafe { tracer.alloc(layout) };
+        assert_eq!(tracer.avg_lifetime(), None);
+
+        NOW_MS.store(30, Ordering::SeqCst);
+        unsafe { tracer.dealloc(first, layout) };
+        assert_eq!(tracer.avg_lifetime(), Some(Duration::from_millis(30)));
+        unsafe { tracer.dealloc(second, layout) };
+        assert_eq!(tracer.avg_lifetime(), Some(Duration::from_millis(25)));
+
+        tracer.reset_stats();
+        assert_eq!(tracer.avg_lifetime(), None);
+        unsafe { tracer.dealloc(kept, layout) };
+        assert_eq!(tracer.avg_lifetime(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn records_are_removed_in_one_batch() {
+        for deduplicate in [false, true].iter() {
+            let tracer = LeakTracer::<4>::builder().deduplicate(*deduplicate).build();
+            let ptrs: Vec<usize> = (1..=6).map(|i| i * 0x100).collect();
+            for ptr in ptrs.iter() {
+                inject(&tracer, fake_record(*ptr, 16, "arena_chunk"));
+            }
+            assert_eq!(tracer.live_bytes(), 96);
+
+            assert_eq!(tracer.remove_records(&ptrs[..4]), 4);
+            assert_eq!(tracer.live_bytes(), 32);
+            assert_eq!(tracer.remove_records(&[ptrs[3], ptrs[4], ptrs[5], 0x10]), 2);
+            assert!(tracer.is_empty());
+            assert_eq!(tracer.live_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn get_leaks_into_reuses_the_map() {
+        let tracer = LeakTracer::<2>::new();
+        for i in 1..=20 {
+            inject(&tracer, fake_record(i * 0x10, 8, "polled"));
+        }
+        let mut leaks = hashbrown::HashMap::default();
+        tracer.get_leaks_into(&mut leaks);
+        assert_eq!(leaks.len(), 20);
+        let capacity = leaks.capacity();
+
+        tracer.remove_records(&[0x10, 0x20]);
+        tracer.get_leaks_into(&mut leaks);
+        assert_eq!(leaks.len(), 18);
+        assert!(!leaks.contains_key(&0x10));
+        assert_eq!(leaks.capacity(), capacity);
+
+        tracer.clear();
+    }
+
+    #[test]
+    fn leaks_group_by_size_class() {
+        let tracer = LeakTracer::<2>::builder()
+            .resolve_mode(ResolveMode::Lazy)
+            .build();
+        let mut unresolved = fake_record(0x60, 4096, "page");
+        unresolved.symbolized = false;
+        unresolved.stack.clear();
+        unresolved.frames.push(0x1234).unwrap();
+        for (ptr, size) in [(0x10, 1), (0x20, 24), (0x30, 32), (0x40, 33), (0x50, 100)].iter() {
+            inject(&tracer, fake_record(*ptr, *size, "object"));
+        }
+        inject(&tracer, unresolved);
+
+        assert_eq!(
+            tracer.leaks_by_size_class(),
+            [
+                (1, 1, 1),
+                (32, 2, 56),
+                (64, 1, 33),
+                (128, 1, 100),
+                (4096, 1, 4096)
+            ]
+        );
+        assert!(!tracer.inner.allocates.lock()[&0x60].symbolized);
+
+        tracer.clear();
+    }
+
+    #[test]
+    fn totals_are_looked_up_by_fingerprint() {
+        let tracer = LeakTracer::<2>::new();
+        inject(&tracer, fake_record(0x10, 100, "known_site"));
+        inject(&tracer, fake_record(0x20, 28, "known_site"));
+        inject(&tracer, fake_record(0x30, 500, "other_site"));
+
+        let known = fake_record::<2>(0, 0, "known_site").fingerprint();
+        assert_eq!(tracer.count_for_fingerprint(known), 2);
+        assert_eq!(tracer.bytes_for_fingerprint(known), 128);
+        let unknown = fake_record::<2>(0, 0, "gone_site").fingerprint();
+        assert_eq!(tracer.count_for_fingerprint(unknown), 0);
+        assert_eq!(tracer.bytes_for_fingerprint(unknown), 0);
+
+        tracer.clear();
+    }
+
+    #[test]
+    fn interval_snapshots_partition_the_allocations() {
+        let tracer: &'static LeakTracer<2> = Box::leak(Box::new(LeakTracer::new()));
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    (0..500)
+                        .map(|_| unsafe { tracer.alloc(layout) } as usize)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut intervals = Vec::new();
+        while workers.iter().any(|worker| !worker.is_finished()) {
+            intervals.push(tracer.reset_and_snapshot());
+        }
+        let ptrs: Vec<usize> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        intervals.push(tracer.reset_and_snapshot());
+
+        let mut seen = std::collections::HashSet::new();
+        for interval in intervals.iter() {
+            assert_eq!(interval.stats.live_allocations, interval.records.len());
+            for ptr in interval.records.keys() {
+                assert!(seen.insert(*ptr), "{:#x} in two intervals", ptr);
+            }
+        }
+        assert_eq!(seen.len(), 2000);
+        assert!(ptrs.iter().all(|ptr| seen.contains(ptr)));
+        let allocs: u64 = intervals
+            .iter()
+            .map(|interval| interval.stats.alloc_count)
+            .sum();
+        assert_eq!(allocs, 2000);
+        assert!(tracer.is_empty());
+        assert_eq!(tracer.live_bytes(), 0);
+
+        for ptr in ptrs {
+            unsafe { tracer.dealloc(ptr as *mut u8, layout) };
+        }
+    }
+
+    #[test]
+    fn sampling_backs_off_under_a_high_rate() {
+        static NOW_MS: AtomicU64 = AtomicU64::new(0);
+        fn fake_clock() -> Duration {
+            Duration::from_millis(NOW_MS.load(Ordering::SeqCst))
+        }
+
+        let tracer = LeakTracer::<2>::builder()
+            .adaptive_sampling(128, 100)
+            .build();
+        tracer.set_clock(fake_clock);
+        let small = Layout::from_size_align(32, 8).unwrap();
+        let large = Layout::from_size_align(256, 8).unwrap();
+        let mut ptrs: Vec<_> = (0..1000).map(|_| unsafe { tracer.alloc(small) }).collect();
+        assert!(tracer.sample_interval() > 1);
+        assert!(tracer.stats().live_allocations < 1000);
+        let recorded = tracer.stats().live_allocations;
+
+        // Large allocations are always recorded.
+        let big = unsafe { tracer.alloc(large) };
+        assert_eq!(tracer.stats().live_allocations, recorded + 1);
+        unsafe { tracer.dealloc(big, large) };
+
+        // Each quiet window halves the interval until every one is recorded.
+        let backed_off = tracer.sample_interval();
+        for window in 1..=16 {
+            NOW_MS.store(window * 2000, Ordering::SeqCst);
+            ptrs.push(unsafe { tracer.alloc(small) });
+        }
+        assert!(tracer.sample_interval() < backed_off);
+        assert_eq!(tracer.sample_interval(), 1);
+
+        for ptr in ptrs {
+            unsafe { tracer.dealloc(ptr, small) };
+        }
+        assert!(tracer.is_empty());
+    }
+
+    #[test]
+    fn size_classes_are_split_by_thread() {
+        let tracer = LeakTracer::<5>::new();
+        let leak = |sizes: &'static [usize]| {
+            let tracer = &tracer;
+            move || {
+                for size in sizes {
+                    unsafe { tracer.alloc(Layout::from_size_align(*size, 8).unwrap()) };
+                }
+                std::thread::current().id().as_u64().get()
+            }
+        };
+        let (small, large) = std::thread::scope(|scope| {
+            let small = scope.spawn(leak(&[16, 16, 24])).join().unwrap();
+            let large = scope.spawn(leak(&[100, 4000])).join().unwrap();
+            (small, large)
+        });
+
+        let threads = tracer.size_class_by_thread();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[&small], [(16, 2, 32), (32, 1, 24)]);
+        assert_eq!(threads[&large], [(128, 1, 100), (4096, 1, 4000)]);
+
+        free_all(&tracer);
+    }
+
+    #[test]
+    fn calibrated_skip_puts_the_caller_on_top() {
+        let tracer = LeakTracer::<4>::new();
+        let first = allocate_widget(&tracer, 48);
+        let skip = tracer.frame_skip();
+        let second = allocate_widget(&tracer, 48);
+        assert_eq!(tracer.frame_skip(), skip);
+
+        let records = tracer.get_leaks();
+        for ptr in [first, second].iter() {
+            let top = &records[&(*ptr as usize)].stack[0];
+            assert!(top.matches("allocate_widget"), "{:?}", top);
+        }
+
+        free_all(&tracer);
     }
 }
@@ -0,0 +1,68 @@
+/// Declares a tracer as the `#[global_allocator]`.
+///
+/// This expands to a `static LEAK_TRACER` of the given type, a
+/// [`LeakTracerDefault`](crate::LeakTracerDefault) if none is given, and a
+/// `fn leak_tracer() -> &'static _` returning it. The first call also
+/// registers it as [`LeakTracer::global`](crate::LeakTracer::global):
+///
+/// ```ignore
+/// leak_detect_allocator::leak_tracer!(LeakTracer<20>, report_at_exit);
+///
+/// fn main() {
+///     leak_tracer().arm();
+///     // ..
+/// }
+/// ```
+///
+/// With `report_at_exit`, the first call to `leak_tracer()` also registers an
+/// `atexit` handler printing the remaining leaks to stderr.
+#[macro_export]
+macro_rules! leak_tracer {
+    () => {
+        $crate::leak_tracer!($crate::LeakTracerDefault);
+    };
+    (report_at_exit) => {
+        $crate::leak_tracer!($crate::LeakTracerDefault, report_at_exit);
+    };
+    ($ty:ty) => {
+        #[global_allocator]
+        static LEAK_TRACER: $ty = <$ty>::new();
+
+        #[allow(dead_code)]
+        fn leak_tracer() -> &'static $ty {
+            static REGISTER: ::std::sync::Once = ::std::sync::Once::new();
+            REGISTER.call_once(|| {
+                LEAK_TRACER.register_global();
+            });
+            &LEAK_TRACER
+        }
+    };
+    ($ty:ty, report_at_exit) => {
+        #[global_allocator]
+        static LEAK_TRACER: $ty = <$ty>::new();
+
+        #[allow(dead_code)]
+        fn leak_tracer() -> &'static $ty {
+            extern "C" fn report() {
+                eprint!("{}", LEAK_TRACER.display_leaks());
+            }
+            static REGISTER: ::std::sync::Once = ::std::sync::Once::new();
+            REGISTER.call_once(|| {
+                LEAK_TRACER.register_global();
+                $crate::__at_exit(report);
+            });
+            &LEAK_TRACER
+        }
+    };
+}
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> std::os::raw::c_int;
+}
+
+#[doc(hidden)]
+pub fn at_exit(callback: extern "C" fn()) {
+    unsafe {
+        atexit(callback);
+    }
+}
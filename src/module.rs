@@ -0,0 +1,68 @@
+//! Which loaded module (executable or shared library) an address is in.
+
+/// What the dynamic linker knows about an address.
+pub(crate) struct ModuleInfo {
+    /// Where the module is loaded.
+    pub base: usize,
+    pub path: Option<&'static std::ffi::CStr>,
+    /// Start of the nearest exported symbol below the address.
+    pub symbol_addr: Option<usize>,
+}
+
+impl ModuleInfo {
+    /// File name of the module, without its directory.
+    pub fn name(&self) -> Option<&'static str> {
+        let path = self.path?.to_str().ok()?;
+        path.rsplit('/').next().filter(|name| !name.is_empty())
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::ModuleInfo;
+    use std::ffi::{c_void, CStr};
+    use std::os::raw::{c_char, c_int};
+
+    #[repr(C)]
+    struct DlInfo {
+        dli_fname: *const c_char,
+        dli_fbase: *mut c_void,
+        dli_sname: *const c_char,
+        dli_saddr: *mut c_void,
+    }
+
+    extern "C" {
+        fn dladdr(addr: *const c_void, info: *mut DlInfo) -> c_int;
+    }
+
+    pub(crate) fn lookup(addr: usize) -> Option<ModuleInfo> {
+        let mut info = DlInfo {
+            dli_fname: std::ptr::null(),
+            dli_fbase: std::ptr::null_mut(),
+            dli_sname: std::ptr::null(),
+            dli_saddr: std::ptr::null_mut(),
+        };
+        if unsafe { dladdr(addr as *const c_void, &mut info) } == 0 {
+            return None;
+        }
+        // Both stay valid as long as the module is loaded.
+        let path = (!info.dli_fname.is_null()).then(|| unsafe { CStr::from_ptr(info.dli_fname) });
+        let symbol_addr = (!info.dli_saddr.is_null()).then_some(info.dli_saddr as usize);
+        Some(ModuleInfo {
+            base: info.dli_fbase as usize,
+            path,
+            symbol_addr,
+        })
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::ModuleInfo;
+
+    pub(crate) fn lookup(_addr: usize) -> Option<ModuleInfo> {
+        None
+    }
+}
+
+pub(crate) use imp::lookup;
@@ -0,0 +1,238 @@
+//! Records with untruncated names, behind the `owned-strings` feature.
+
+use crate::{AllocationRecord, Call, LeakTracer, ReentrancyGuard};
+use backtrace::{BytesOrWideString, Symbol};
+use std::alloc::System;
+use std::fmt::{self, Debug, Display};
+use std::ops::Deref;
+use std::time::Duration;
+use widestring::U16Str;
+
+/// A string allocated from `System`, so the tracer can make one without
+/// recording it.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SystemString(Vec<u8, System>);
+
+impl SystemString {
+    fn from_chars(chars: impl Iterator<Item = char>) -> Self {
+        let mut bytes = Vec::new_in(System);
+        for c in chars {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        Self(bytes)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from chars.
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+}
+
+impl From<&str> for SystemString {
+    fn from(value: &str) -> Self {
+        Self::from_chars(value.chars())
+    }
+}
+
+impl Deref for SystemString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Display for SystemString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Debug for SystemString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// [`Call`] with names and paths of any length.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OwnedCall {
+    pub name: Option<SystemString>,
+    pub filename: Option<SystemString>,
+    pub line: Option<u32>,
+    pub col: Option<u32>,
+
+    pub addr: usize,
+    pub ip: usize,
+}
+
+impl From<&Symbol> for OwnedCall {
+    fn from(value: &Symbol) -> Self {
+        let filename = value.filename_raw().map(|x| match x {
+            BytesOrWideString::Bytes(bytes) => {
+                SystemString::from_chars(bytes.utf8_chunks().flat_map(|chunk| {
+                    let invalid =
+                        (!chunk.invalid().is_empty()).then_some(char::REPLACEMENT_CHARACTER);
+                    chunk.valid().chars().chain(invalid)
+                }))
+            }
+            BytesOrWideString::Wide(bytes) => {
+                SystemString::from_chars(U16Str::from_slice(bytes).chars_lossy())
+            }
+        });
+        Self {
+            name: value
+                .name()
+                .and_then(|x| x.as_str())
+                .map(SystemString::from),
+            filename,
+            line: value.lineno(),
+            col: value.colno(),
+            addr: value.addr().map_or(0, |x| x as usize),
+            ip: 0,
+        }
+    }
+}
+
+/// Copies a call already captured, truncated names included.
+impl<const NAME_LEN: usize> From<&Call<NAME_LEN>> for OwnedCall {
+    fn from(value: &Call<NAME_LEN>) -> Self {
+        Self {
+            name: value.name.as_deref().map(SystemString::from),
+            filename: value.filename.as_deref().map(SystemString::from),
+            line: value.line,
+            col: value.col,
+            addr: value.addr,
+            ip: value.ip,
+        }
+    }
+}
+
+/// A live allocation with its stack in [`OwnedCall`]s, see
+/// [`LeakTracer::get_leaks_owned`].
+#[derive(Debug, Clone)]
+pub struct OwnedRecord {
+    pub size: usize,
+    pub align: usize,
+    pub ptr: usize,
+    pub thread_id: u64,
+    pub seq: u64,
+    pub allocated_at: Duration,
+    pub multiplicity: usize,
+    pub stack: Vec<OwnedCall, System>,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Like [`LeakTracer::get_leaks_sorted`], with names and filenames in
+    /// full rather than cut at `NAME_LEN`. The frames are symbolized again
+    /// from their addresses, so this is as slow as resolving lazily; records
+    /// without addresses (e.g. from a backtrace provider) keep their calls.
+    pub fn get_leaks_owned(&self) -> Vec<OwnedRecord> {
+        let _guard = ReentrancyGuard::enter();
+        let mut records: Vec<_> = self.inner.allocates.lock().values().cloned().collect();
+        records.sort_by_key(|record| (record.seq, record.ptr));
+        records
+            .iter()
+            .map(|record| OwnedRecord {
+                size: record.size,
+                align: record.align,
+                ptr: record.ptr,
+                thread_id: record.thread_id,
+                seq: record.seq,
+                allocated_at: record.allocated_at,
+                multiplicity: record.multiplicity,
+                stack: self.owned_stack(record),
+            })
+            .collect()
+    }
+
+    fn owned_stack(
+        &self,
+        record: &AllocationRecord<STACK_SIZE, NAME_LEN>,
+    ) -> Vec<OwnedCall, System> {
+        let mut stack = Vec::new_in(System);
+        if record.frames.is_empty() {
+            stack.extend(record.stack.iter().map(OwnedCall::from));
+            return stack;
+        }
+        let symbols = self.inner.symbols.read();
+        for &ip in record.frames.iter() {
+            let from = stack.len();
+            unsafe {
+                backtrace::resolve_unsynchronized(ip as *mut std::ffi::c_void, |symbol| {
+                    stack.push(OwnedCall {
+                        ip,
+                        ..symbol.into()
+                    });
+                });
+            }
+            if stack[from..].iter().any(|call| call.name.is_some()) {
+                continue;
+            }
+            // Registered names are stored truncated already.
+            let registered = symbols
+                .partition_point(|(addr, _)| *addr <= ip)
+                .checked_sub(1)
+                .map(|idx| &symbols[idx]);
+            if let Some((addr, name)) = registered {
+                let name = Some(SystemString::from(name.as_str()));
+                match stack.get_mut(from) {
+                    Some(call) => call.name = name,
+                    None => stack.push(OwnedCall {
+                        name,
+                        filename: None,
+                        line: None,
+                        col: None,
+                        addr: *addr,
+                        ip,
+                    }),
+                }
+            }
+        }
+        stack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::frame_fn;
+    use crate::LeakTracer;
+    use std::alloc::{GlobalAlloc, Layout};
+
+    const LONG_NAME: &str = "very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_long_frame_name";
+
+    frame_fn! {
+        fn very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_long_frame_name(tracer: &impl GlobalAlloc) -> *mut u8 {
+            unsafe { tracer.alloc(Layout::from_size_align(8, 8).unwrap()) }
+        }
+    }
+
+    #[test]
+    fn long_names_are_kept_in_full() {
+        let tracer = LeakTracer::<16>::new();
+        let ptr = very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_very_long_frame_name(&tracer);
+        assert!(LONG_NAME.len() > 500);
+
+        let truncated = tracer.get_leaks().remove(&(ptr as usize)).unwrap();
+        assert!(truncated.stack.iter().all(|call| !call
+            .name
+            .as_ref()
+            .is_some_and(|name| name.contains(LONG_NAME))));
+
+        let owned = tracer.get_leaks_owned();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].ptr, ptr as usize);
+        let call = owned[0]
+            .stack
+            .iter()
+            .find(|call| {
+                call.name
+                    .as_ref()
+                    .is_some_and(|name| name.contains(LONG_NAME))
+            })
+            .unwrap();
+        assert!(call.name.as_ref().unwrap().len() > 500);
+
+        unsafe { tracer.dealloc(ptr, Layout::from_size_align(8, 8).unwrap()) };
+    }
+}
@@ -0,0 +1,453 @@
+//! Live allocations grouped by call site.
+
+use crate::{
+    AllocationRecord, Call, FnvHasher, LeakTracer, ReentrancyGuard, ResolveMode, DEFAULT_NAME_LEN,
+};
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashMap;
+use std::alloc::System;
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+
+/// The live allocations from one call site, see [`LeakReport`].
+#[derive(Debug, Clone)]
+pub struct LeakGroup<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    /// [`AllocationRecord::fingerprint`] of the allocations, or the hash of
+    /// their attribution frame, see
+    /// [`LeakTracerBuilder::attribution_root`](crate::LeakTracerBuilder::attribution_root).
+    pub fingerprint: u64,
+    pub count: usize,
+    pub bytes: usize,
+    /// One of the allocations, for its stack.
+    pub sample: AllocationRecord<STACK_SIZE, NAME_LEN>,
+}
+
+/// The largest groups of a report, and what the others add up to, see
+/// [`LeakReport::top_n_groups`].
+#[derive(Debug, Clone)]
+pub struct TopGroups<'a, const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    /// Most bytes first.
+    pub groups: Vec<&'a LeakGroup<STACK_SIZE, NAME_LEN>>,
+    /// Number of groups left out.
+    pub rest_groups: usize,
+    pub rest_count: usize,
+    pub rest_bytes: usize,
+}
+
+/// Live allocations grouped by [`AllocationRecord::fingerprint`], made by
+/// [`LeakTracer::report`]. Reports from several windows or processes can be
+/// combined with [`LeakReport::merge`].
+#[derive(Debug, Clone)]
+pub struct LeakReport<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    groups: HashMap<u64, LeakGroup<STACK_SIZE, NAME_LEN>, DefaultHashBuilder, System>,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> Default for LeakReport<STACK_SIZE, NAME_LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakReport<STACK_SIZE, NAME_LEN> {
+    pub fn new() -> Self {
+        Self {
+            groups: HashMap::default(),
+        }
+    }
+
+    /// Number of call sites.
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn get(&self, fingerprint: u64) -> Option<&LeakGroup<STACK_SIZE, NAME_LEN>> {
+        self.groups.get(&fingerprint)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &LeakGroup<STACK_SIZE, NAME_LEN>> {
+        self.groups.values()
+    }
+
+    /// Number of allocations over all groups.
+    pub fn total_count(&self) -> usize {
+        self.groups.values().map(|group| group.count).sum()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.groups.values().map(|group| group.bytes).sum()
+    }
+
+    /// The `n` groups holding the most bytes, with the rest summed up, to
+    /// keep exports of huge reports small.
+    pub fn top_n_groups(&self, n: usize) -> TopGroups<'_, STACK_SIZE, NAME_LEN> {
+        let _guard = ReentrancyGuard::enter();
+        let mut groups: Vec<_> = self.groups.values().collect();
+        groups.sort_by_key(|group| (std::cmp::Reverse(group.bytes), group.fingerprint));
+        let rest = groups.split_off(n.min(groups.len()));
+        TopGroups {
+            groups,
+            rest_groups: rest.len(),
+            rest_count: rest.iter().map(|group| group.count).sum(),
+            rest_bytes: rest.iter().map(|group| group.bytes).sum(),
+        }
+    }
+
+    fn add(&mut self, group: LeakGroup<STACK_SIZE, NAME_LEN>) {
+        match self.groups.get_mut(&group.fingerprint) {
+            Some(existing) => {
+                existing.count += group.count;
+                existing.bytes += group.bytes;
+            }
+            None => {
+                self.groups.insert(group.fingerprint, group);
+            }
+        }
+    }
+
+    /// Adds the groups of `other` to this report, summing those of the same
+    /// call site. Samples already here are kept.
+    pub fn merge(&mut self, other: &Self) {
+        for group in other.groups.values() {
+            self.add(group.clone());
+        }
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Groups the live allocations by call site.
+    pub fn report(&self) -> LeakReport<STACK_SIZE, NAME_LEN> {
+        if self.options.flush_before_report {
+            self.flush_pending();
+        }
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve();
+        }
+
+        let _guard = ReentrancyGuard::enter();
+        let mut report = LeakReport::new();
+        for record in self.inner.allocates.lock().values() {
+            let fingerprint = match self.attribution_frame(record) {
+                Some(call) => {
+                    let mut hasher = FnvHasher::default();
+                    call.hash(&mut hasher);
+                    hasher.finish()
+                }
+                None => record.fingerprint(),
+            };
+            report.add(LeakGroup {
+                fingerprint,
+                count: record.multiplicity,
+                bytes: record.size * record.multiplicity,
+                sample: record.clone(),
+            });
+        }
+        report
+    }
+
+    /// The frame `record` is attributed to, if the tracer has an attribution
+    /// root and the stack a frame under it.
+    fn attribution_frame<'a>(
+        &self,
+        record: &'a AllocationRecord<STACK_SIZE, NAME_LEN>,
+    ) -> Option<&'a Call<NAME_LEN>> {
+        record.attribution_frame(self.options.attribution_root?)
+    }
+
+    /// Writes one line per call site, most bytes first:
+    /// `<fingerprint> <bytes> bytes in <count> allocs @ <top frame> (<file>:<line>)`,
+    /// see [`AllocationRecord::fingerprint_hex`] and
+    /// [`AllocationRecord::top_frame`]. With an attribution root the
+    /// attribution frame is shown instead of the top frame.
+    pub fn print_summary_lines<W: Write>(&self, w: W) -> std::io::Result<()> {
+        self.print_top_summary_lines(w, usize::MAX)
+    }
+
+    /// Like [`print_summary_lines`](Self::print_summary_lines) for the `n`
+    /// biggest sites only, followed by
+    /// `rest <bytes> bytes in <count> allocs from <sites> sites` if any were
+    /// left out, see [`LeakReport::top_n_groups`].
+    pub fn print_top_summary_lines<W: Write>(&self, mut w: W, n: usize) -> std::io::Result<()> {
+        let report = self.report();
+        let top = report.top_n_groups(n);
+        for group in top.groups.iter() {
+            write!(
+                w,
+                "{:016x} {} bytes in {} allocs @ ",
+                group.fingerprint, group.bytes, group.count
+            )?;
+            let frame = self
+                .attribution_frame(&group.sample)
+                .or_else(|| group.sample.top_frame());
+            match frame {
+                Some(call) => {
+                    let name = call.name.as_deref().unwrap_or("[unknown]");
+                    let file = call.filename.as_deref().unwrap_or("[unknown file]");
+                    write!(w, "{name} ({file}")?;
+                    if let Some(line) = call.line {
+                        write!(w, ":{line}")?;
+                    }
+                    writeln!(w, ")")?;
+                }
+                None => writeln!(w, "[no frames]")?,
+            }
+        }
+        if top.rest_groups > 0 {
+            writeln!(
+                w,
+                "rest {} bytes in {} allocs from {} sites",
+                top.rest_bytes, top.rest_count, top.rest_groups
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The [`report`](Self::report) as a JSON array with one object per
+    /// site, most bytes first:
+    /// `{"fingerprint": "<hex>", "count": .., "total_bytes": .., "top_frames":
+    /// [{"name": .., "file": .., "line": ..}, ..]}`. The fingerprint is a
+    /// string since JSON numbers can't hold every `u64`; the frames are the
+    /// stack of one of the allocations, missing fields are `null`.
+    pub fn leak_summary_json(&self) -> String {
+        self.leak_summary_json_top(usize::MAX)
+    }
+
+    /// Like [`leak_summary_json`](Self::leak_summary_json) for the `n`
+    /// biggest sites only, followed by
+    /// `{"rest": {"groups": .., "count": .., "total_bytes": ..}}` if any were
+    /// left out, see [`LeakReport::top_n_groups`].
+    pub fn leak_summary_json_top(&self, n: usize) -> String {
+        let report = self.report();
+        let top = report.top_n_groups(n);
+        let mut out = String::from("[");
+        for (i, group) in top.groups.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"fingerprint\":\"{:016x}\",\"count\":{},\"total_bytes\":{},\"top_frames\":[",
+                group.fingerprint, group.count, group.bytes
+            );
+            for (i, call) in group.sample.stack.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"name\":");
+                push_json_str(&mut out, call.name.as_deref());
+                out.push_str(",\"file\":");
+                push_json_str(&mut out, call.filename.as_deref());
+                match call.line {
+                    Some(line) => {
+                        let _ = write!(out, ",\"line\":{line}}}");
+                    }
+                    None => out.push_str(",\"line\":null}"),
+                }
+            }
+            out.push_str("]}");
+        }
+        if top.rest_groups > 0 {
+            if !top.groups.is_empty() {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"rest\":{{\"groups\":{},\"count\":{},\"total_bytes\":{}}}}}",
+                top.rest_groups, top.rest_count, top.rest_bytes
+            );
+        }
+        out.push(']');
+        out
+    }
+}
+
+/// Appends `s` as a JSON string, or `null`.
+fn push_json_str(out: &mut String, s: Option<&str>) {
+    let Some(s) = s else {
+        out.push_str("null");
+        return;
+    };
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{fake_call, fake_record, inject};
+    use crate::LeakTracer;
+
+    #[test]
+    fn merging_sums_shared_sites() {
+        let first = LeakTracer::<2>::new();
+        inject(&first, fake_record(0x10, 100, "shared"));
+        inject(&first, fake_record(0x20, 50, "first_only"));
+        let second = LeakTracer::<2>::new();
+        inject(&second, fake_record(0x10, 30, "shared"));
+        inject(&second, fake_record(0x30, 20, "shared"));
+
+        let mut report = first.report();
+        report.merge(&second.report());
+
+        let shared = fake_record::<2>(0, 0, "shared").fingerprint();
+        let first_only = fake_record::<2>(0, 0, "first_only").fingerprint();
+        assert_eq!(report.len(), 2);
+        let group = report.get(shared).unwrap();
+        assert_eq!((group.count, group.bytes), (3, 150));
+        let group = report.get(first_only).unwrap();
+        assert_eq!((group.count, group.bytes), (1, 50));
+        assert_eq!((report.total_count(), report.total_bytes()), (4, 200));
+    }
+
+    #[test]
+    fn summary_lines_start_with_the_biggest_site() {
+        let tracer = LeakTracer::<2>::new();
+        inject(&tracer, fake_record(0x10, 100, "small_site"));
+        inject(&tracer, fake_record(0x20, 300, "big_site"));
+        inject(&tracer, fake_record(0x30, 300, "big_site"));
+
+        let mut out = Vec::new();
+        tracer.print_summary_lines(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        let big = fake_record::<2>(0, 0, "big_site").fingerprint_hex();
+        let small = fake_record::<2>(0, 0, "small_site").fingerprint_hex();
+        assert_eq!(
+            lines,
+            [
+                format!("{big} 600 bytes in 2 allocs @ big_site (src/fake.rs:1)"),
+                format!("{small} 100 bytes in 1 allocs @ small_site (src/fake.rs:1)"),
+            ]
+        );
+    }
+
+    #[test]
+    fn std_helpers_group_under_the_user_frame() {
+        let tracer = LeakTracer::<3>::builder()
+            .attribution_root("my_app::")
+            .build();
+        for (ptr, helper) in [
+            (0x10, "alloc::raw_vec::RawVec::reserve"),
+            (0x20, "alloc::string::String::push_str"),
+        ]
+        .iter()
+        {
+            let mut record = fake_record(*ptr, 32, "__rust_alloc");
+            record.stack.push(fake_call(helper)).unwrap();
+            record.stack.push(fake_call("my_app::load_config")).unwrap();
+            inject(&tracer, record);
+        }
+        inject(
+            &tracer,
+            fake_record(0x30, 8, "alloc::string::String::push_str"),
+        );
+
+        let report = tracer.report();
+        assert_eq!(report.len(), 2);
+        let attributed = report.groups().find(|group| group.count == 2).unwrap();
+        assert_eq!(attributed.bytes, 64);
+        assert_eq!(attributed.sample.stack.len(), 3);
+
+        let mut out = Vec::new();
+        tracer.print_summary_lines(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let first = out.lines().next().unwrap();
+        assert!(first.ends_with("64 bytes in 2 allocs @ my_app::load_config (src/fake.rs:1)"));
+    }
+
+    #[test]
+    fn top_groups_sum_up_the_rest() {
+        let tracer = LeakTracer::<2>::new();
+        inject(&tracer, fake_record(0x10, 1000, "biggest_site"));
+        inject(&tracer, fake_record(0x20, 200, "middle_site"));
+        inject(&tracer, fake_record(0x30, 100, "small_site"));
+        inject(&tracer, fake_record(0x40, 50, "small_site"));
+
+        let report = tracer.report();
+        let top = report.top_n_groups(1);
+        assert_eq!(top.groups.len(), 1);
+        assert_eq!(top.groups[0].bytes, 1000);
+        assert_eq!(
+            (top.rest_groups, top.rest_count, top.rest_bytes),
+            (2, 3, 350)
+        );
+        assert_eq!(report.top_n_groups(10).rest_groups, 0);
+
+        let mut out = Vec::new();
+        tracer.print_top_summary_lines(&mut out, 1).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        let biggest = fake_record::<2>(0, 0, "biggest_site").fingerprint_hex();
+        assert_eq!(
+            lines,
+            [
+                format!("{biggest} 1000 bytes in 1 allocs @ biggest_site (src/fake.rs:1)"),
+                "rest 350 bytes in 3 allocs from 2 sites".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_json_has_one_entry_per_site() {
+        let tracer = LeakTracer::<2>::new();
+        inject(&tracer, fake_record(0x10, 100, "small_site"));
+        inject(&tracer, fake_record(0x20, 300, "big_site"));
+        inject(&tracer, fake_record(0x30, 300, "big_site"));
+        inject(&tracer, fake_record(0x40, 8, "odd \"site\"\\"));
+
+        let big = fake_record::<2>(0, 0, "big_site").fingerprint_hex();
+        let small = fake_record::<2>(0, 0, "small_site").fingerprint_hex();
+        let odd = fake_record::<2>(0, 0, "odd \"site\"\\").fingerprint_hex();
+        let frame = |name: &str| format!(r#"[{{"name":"{name}","file":"src/fake.rs","line":1}}]"#);
+        assert_eq!(
+            tracer.leak_summary_json(),
+            format!(
+                r#"[{{"fingerprint":"{big}","count":2,"total_bytes":600,"top_frames":{}}},{{"fingerprint":"{small}","count":1,"total_bytes":100,"top_frames":{}}},{{"fingerprint":"{odd}","count":1,"total_bytes":8,"top_frames":{}}}]"#,
+                frame("big_site"),
+                frame("small_site"),
+                frame(r#"odd \"site\"\\"#),
+            )
+        );
+
+        tracer.clear();
+        assert_eq!(LeakTracer::<2>::new().leak_summary_json(), "[]");
+    }
+
+    #[test]
+    fn summary_json_top_sums_up_the_rest() {
+        let tracer = LeakTracer::<2>::new();
+        inject(&tracer, fake_record(0x10, 100, "small_site"));
+        inject(&tracer, fake_record(0x20, 300, "big_site"));
+        inject(&tracer, fake_record(0x30, 300, "big_site"));
+        inject(&tracer, fake_record(0x40, 8, "tiny_site"));
+
+        let big = fake_record::<2>(0, 0, "big_site").fingerprint_hex();
+        assert_eq!(
+            tracer.leak_summary_json_top(1),
+            format!(
+                r#"[{{"fingerprint":"{big}","count":2,"total_bytes":600,"top_frames":[{{"name":"big_site","file":"src/fake.rs","line":1}}]}},{{"rest":{{"groups":2,"count":2,"total_bytes":108}}}}]"#
+            )
+        );
+        assert_eq!(
+            tracer.leak_summary_json_top(0),
+            r#"[{"rest":{"groups":3,"count":4,"total_bytes":708}}]"#
+        );
+        assert_eq!(tracer.leak_summary_json_top(3), tracer.leak_summary_json());
+
+        tracer.clear();
+    }
+}
@@ -0,0 +1,202 @@
+//! Background symbolization for [`ResolveMode::Lazy`] tracers.
+
+use crate::{LeakTracer, ReentrancyGuard, ResolveMode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Keeps the thread started by [`LeakTracer::start_resolver`] running.
+/// Dropping it stops the thread.
+pub struct ResolverHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ResolverHandle {
+    /// Stops the thread and waits for it to exit. Records queued but not
+    /// resolved yet are left to [`LeakTracer::resolve`].
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ResolverHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Starts a thread symbolizing new records in the background, checking for
+    /// them every `interval`. The allocation path then only queues them.
+    ///
+    /// Only useful in [`ResolveMode::Lazy`]; for eager tracers the thread has
+    /// nothing to do. Queries such as [`LeakTracer::get_leaks`] and
+    /// [`LeakTracer::report`] never see records the thread hasn't got to:
+    /// they symbolize those themselves first. Call
+    /// [`LeakTracer::flush_pending`] before them to have that done ahead of
+    /// time instead.
+    pub fn start_resolver(&'static self, interval: Duration) -> ResolverHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        self.inner.resolver_running.store(true, Ordering::SeqCst);
+        let thread = std::thread::spawn(move || {
+            // The thread belongs to the tracer, none of its allocations count.
+            let _guard = ReentrancyGuard::enter();
+            while !thread_stop.load(Ordering::SeqCst) {
+                self.resolve_queued();
+                std::thread::park_timeout(interval);
+            }
+            self.inner.resolver_running.store(false, Ordering::SeqCst);
+        });
+        ResolverHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Blocks until the resolver queue is drained: resolves everything
+    /// queued on the calling thread, and waits for what the resolver thread
+    /// is busy with.
+    ///
+    /// Every record allocated before the call is resolved once it returns,
+    /// so reports taken after it don't need to symbolize anything.
+    /// Allocations made meanwhile by other threads may still be queued.
+    ///
+    /// With [`LeakTracerBuilder::flush_before_report`](crate::LeakTracerBuilder::flush_before_report),
+    /// [`LeakTracer::report`] and [`LeakTracer::get_leaks`] call it first.
+    pub fn flush_pending(&self) {
+        if self.options.resolve_mode != ResolveMode::Lazy {
+            return;
+        }
+        let _guard = ReentrancyGuard::enter();
+        self.resolve_queued();
+        while self.inner.resolving.load(Ordering::SeqCst) > 0 {
+            std::thread::yield_now();
+        }
+    }
+
+    /// The same as [`LeakTracer::flush_pending`].
+    pub fn flush(&self) {
+        self.flush_pending();
+    }
+
+    fn resolve_queued(&self) {
+        let queued = {
+            let mut queue = self.inner.resolve_queue.lock();
+            // Counted before the lock is released, so `flush_pending` can't miss them.
+            self.inner
+                .resolving
+                .fetch_add(queue.len(), Ordering::SeqCst);
+            std::mem::replace(&mut *queue, Vec::new_in(std::alloc::System))
+        };
+        self.resolve_records(&queued, &mut |_, _| {});
+        self.inner
+            .resolving
+            .fetch_sub(queued.len(), Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{LeakTracer, ResolveMode};
+    use std::alloc::{GlobalAlloc, Layout};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    #[test]
+    fn flush_pending_leaves_every_record_resolved() {
+        let tracer: &'static LeakTracer<6> = Box::leak(Box::new(
+            LeakTracer::builder()
+                .resolve_mode(ResolveMode::Lazy)
+                .build(),
+        ));
+        let resolver = tracer.start_resolver(Duration::from_millis(1));
+
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptrs: Vec<*mut u8> = (0..3).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        tracer.flush_pending();
+
+        let allocates = tracer.inner.allocates.lock();
+        assert_eq!(allocates.len(), 3);
+        for record in allocates.values() {
+            assert!(record.symbolized);
+            assert!(!record.stack.is_empty());
+        }
+        drop(allocates);
+
+        resolver.stop();
+        for ptr in ptrs {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn reports_after_flush_pending_are_fully_resolved() {
+        let tracer: &'static LeakTracer<6> = Box::leak(Box::new(
+            LeakTracer::builder()
+                .resolve_mode(ResolveMode::Lazy)
+                .build(),
+        ));
+        // Long enough that the thread won't get back to the queue by itself.
+        let resolver = tracer.start_resolver(Duration::from_secs(60));
+
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let ptrs: Vec<*mut u8> = (0..5).map(|_| unsafe { tracer.alloc(layout) }).collect();
+        tracer.flush_pending();
+        assert!(tracer.inner.resolve_queue.lock().is_empty());
+        assert!(tracer
+            .inner
+            .allocates
+            .lock()
+            .values()
+            .all(|record| record.symbolized));
+
+        let report = tracer.report();
+        assert_eq!(report.total_count(), 5);
+        for group in report.groups() {
+            assert!(group.sample.symbolized);
+            assert!(!group.sample.stack.is_empty());
+        }
+
+        resolver.stop();
+        for ptr in ptrs {
+            unsafe { tracer.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn flush_before_report_drains_the_queue() {
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        for flush in [false, true].iter() {
+            let tracer: &'static LeakTracer<6> = Box::leak(Box::new(
+                LeakTracer::builder()
+                    .resolve_mode(ResolveMode::Lazy)
+                    .flush_before_report(*flush)
+                    .build(),
+            ));
+            // A resolver thread that never gets to the queue by itself.
+            tracer.inner.resolver_running.store(true, Ordering::SeqCst);
+
+            let reported = unsafe { tracer.alloc(layout) };
+            assert_eq!(tracer.report().total_count(), 1);
+            assert_eq!(tracer.inner.resolve_queue.lock().is_empty(), *flush);
+            let listed = unsafe { tracer.alloc(layout) };
+            assert!(tracer.get_leaks().values().all(|record| record.symbolized));
+            assert_eq!(tracer.inner.resolve_queue.lock().is_empty(), *flush);
+
+            for ptr in [reported, listed].iter() {
+                unsafe { tracer.dealloc(*ptr, layout) };
+            }
+        }
+    }
+}
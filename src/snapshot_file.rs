@@ -0,0 +1,418 @@
+//! Saving the live records to a file, and reading them back for
+//! [`LeakTracer::load_into`] in another process.
+
+use crate::{
+    truncated, truncated_path, AllocOrigin, AllocationRecord, Call, LeakSnapshot, LeakTracer,
+    ReentrancyGuard, TracerSnapshot, DEFAULT_NAME_LEN,
+};
+use heapless::Vec as HeaplessVec;
+use std::alloc::System;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// First line of every snapshot file, with the version of the format.
+const HEADER: &str = "leak-detect-allocator snapshot 1";
+
+/// Records saved with [`SnapshotFile::save`] and read back with
+/// [`SnapshotFile::open`], e.g. to analyze a run offline by loading them
+/// into a fresh tracer with [`LeakTracer::load_into`].
+///
+/// The file is text, a line per record and per frame. Stacks and strings
+/// longer than the reading tracer's `STACK_SIZE` and `NAME_LEN` are
+/// truncated.
+pub struct SnapshotFile<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    records: Vec<AllocationRecord<STACK_SIZE, NAME_LEN>, System>,
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> SnapshotFile<STACK_SIZE, NAME_LEN> {
+    pub fn new(records: impl IntoIterator<Item = AllocationRecord<STACK_SIZE, NAME_LEN>>) -> Self {
+        let mut kept = Vec::new_in(System);
+        kept.extend(records);
+        Self { records: kept }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn records(&self) -> &[AllocationRecord<STACK_SIZE, NAME_LEN>] {
+        &self.records
+    }
+
+    /// Writes the records to `path`, replacing the file if there is one.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{}", HEADER)?;
+        for record in &self.records {
+            writeln!(
+                w,
+                "record\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                record.ptr,
+                record.size,
+                record.usable_size,
+                record.align,
+                record.thread_id,
+                Escaped(record.thread_name.as_deref()),
+                Escaped(record.label.as_deref()),
+                record.seq,
+                record.allocated_at.as_nanos(),
+                match record.origin {
+                    AllocOrigin::Alloc => "alloc",
+                    AllocOrigin::Realloc => "realloc",
+                },
+                record.realloc_count,
+                record.multiplicity,
+                record.stack_key,
+                record.symbolized,
+            )?;
+            for ip in &record.frames {
+                writeln!(w, "frame\t{}", ip)?;
+            }
+            for call in &record.stack {
+                write_call(&mut w, "call", call)?;
+            }
+            if let Some(stack) = &record.last_realloc_stack {
+                writeln!(w, "moved")?;
+                for call in stack.iter() {
+                    write_call(&mut w, "moved_call", call)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a file written by [`SnapshotFile::save`].
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from<R: BufRead>(r: R) -> io::Result<Self> {
+        let mut lines = r.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid_data(1, "not a snapshot file"));
+        }
+        let mut records = Vec::new_in(System);
+        for (index, line) in lines.enumerate() {
+            // The header is line 1.
+            let number = index + 2;
+            let line = line?;
+            let mut fields = Fields {
+                fields: line.split('\t'),
+                line: number,
+            };
+            let kind = fields.text()?;
+            if kind == "record" {
+                records.push(fields.record()?);
+                continue;
+            }
+            let record = records
+                .last_mut()
+                .ok_or_else(|| invalid_data(number, "frame before any record"))?;
+            match kind {
+                "frame" => {
+                    let _ = record.frames.push(fields.parse()?);
+                }
+                "call" => {
+                    let _ = record.stack.push(fields.call()?);
+                }
+                "moved" => {
+                    record.last_realloc_stack = Some(Box::new_in(HeaplessVec::new(), System))
+                }
+                "moved_call" => {
+                    let call = fields.call()?;
+                    let stack = record
+                        .last_realloc_stack
+                        .as_mut()
+                        .ok_or_else(|| invalid_data(number, "moved_call without moved"))?;
+                    let _ = stack.push(call);
+                }
+                _ => return Err(invalid_data(number, "unknown line")),
+            }
+            fields.end()?;
+        }
+        Ok(Self { records })
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> IntoIterator
+    for SnapshotFile<STACK_SIZE, NAME_LEN>
+{
+    type Item = AllocationRecord<STACK_SIZE, NAME_LEN>;
+    type IntoIter = std::vec::IntoIter<AllocationRecord<STACK_SIZE, NAME_LEN>, System>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.into_iter()
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> From<LeakSnapshot<STACK_SIZE, NAME_LEN>>
+    for SnapshotFile<STACK_SIZE, NAME_LEN>
+{
+    fn from(snapshot: LeakSnapshot<STACK_SIZE, NAME_LEN>) -> Self {
+        Self::new(snapshot)
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> From<TracerSnapshot<STACK_SIZE, NAME_LEN>>
+    for SnapshotFile<STACK_SIZE, NAME_LEN>
+{
+    fn from(snapshot: TracerSnapshot<STACK_SIZE, NAME_LEN>) -> Self {
+        Self::new(snapshot.records.into_values())
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// The live records, oldest first, to be written with
+    /// [`SnapshotFile::save`]. Lazily resolved records are saved with the
+    /// frames only, call [`LeakTracer::resolve`] first to keep their names.
+    pub fn snapshot_file(&self) -> SnapshotFile<STACK_SIZE, NAME_LEN> {
+        let _guard = ReentrancyGuard::enter();
+        let mut snapshot = SnapshotFile::new(self.inner.allocates.lock().values().cloned());
+        snapshot.records.sort_by_key(|record| record.seq);
+        snapshot
+    }
+}
+
+fn write_call<W: Write, const NAME_LEN: usize>(
+    w: &mut W,
+    kind: &str,
+    call: &Call<NAME_LEN>,
+) -> io::Result<()> {
+    writeln!(
+        w,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        kind,
+        call.ip,
+        call.addr,
+        Escaped(call.name.as_deref()),
+        Escaped(call.filename.as_deref()),
+        Optional(call.line),
+        Optional(call.col),
+        Optional(call.module),
+        call.offset,
+    )
+}
+
+fn invalid_data(line: usize, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("snapshot line {}: {}", line, what),
+    )
+}
+
+/// A string field, empty for `None` and `=` followed by the string with
+/// tabs, line breaks and backslashes escaped otherwise.
+struct Escaped<'a>(Option<&'a str>);
+
+impl std::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(s) = self.0 else {
+            return Ok(());
+        };
+        f.write_str("=")?;
+        for c in s.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '\t' => f.write_str("\\t")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                c => std::fmt::Write::write_char(f, c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A number field, empty for `None`.
+struct Optional<T>(Option<T>);
+
+impl<T: std::fmt::Display> std::fmt::Display for Optional<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Fields<'a> {
+    fields: std::str::Split<'a, char>,
+    line: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn text(&mut self) -> io::Result<&'a str> {
+        self.fields
+            .next()
+            .ok_or_else(|| invalid_data(self.line, "missing field"))
+    }
+
+    fn parse<T: FromStr>(&mut self) -> io::Result<T> {
+        let line = self.line;
+        self.text()?
+            .parse()
+            .map_err(|_| invalid_data(line, "bad number"))
+    }
+
+    fn optional<T: FromStr>(&mut self) -> io::Result<Option<T>> {
+        let line = self.line;
+        match self.text()? {
+            "" => Ok(None),
+            text => text
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid_data(line, "bad number")),
+        }
+    }
+
+    /// An [`Escaped`] string, unescaped.
+    fn string(&mut self) -> io::Result<Option<String>> {
+        let line = self.line;
+        let text = self.text()?;
+        if text.is_empty() {
+            return Ok(None);
+        }
+        let text = text
+            .strip_prefix('=')
+            .ok_or_else(|| invalid_data(line, "bad string"))?;
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            out.push(match chars.next() {
+                Some('\\') => '\\',
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                _ => return Err(invalid_data(line, "bad escape")),
+            });
+        }
+        Ok(Some(out))
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        match self.fields.next() {
+            None => Ok(()),
+            Some(_) => Err(invalid_data(self.line, "extra field")),
+        }
+    }
+
+    fn call<const NAME_LEN: usize>(&mut self) -> io::Result<Call<NAME_LEN>> {
+        Ok(Call {
+            ip: self.parse()?,
+            addr: self.parse()?,
+            name: self.string()?.map(|name| truncated(&name)),
+            filename: self.string()?.map(|path| truncated_path(path.chars())),
+            line: self.optional()?,
+            col: self.optional()?,
+            module: self.optional()?,
+            offset: self.parse()?,
+        })
+    }
+
+    fn record<const STACK_SIZE: usize, const NAME_LEN: usize>(
+        &mut self,
+    ) -> io::Result<AllocationRecord<STACK_SIZE, NAME_LEN>> {
+        let line = self.line;
+        let record = AllocationRecord {
+            ptr: self.parse()?,
+            size: self.parse()?,
+            usable_size: self.parse()?,
+            align: self.parse()?,
+            thread_id: self.parse()?,
+            thread_name: self.string()?.map(|name| truncated(&name)),
+            label: self.string()?.map(|label| truncated(&label)),
+            seq: self.parse()?,
+            allocated_at: Duration::from_nanos(self.parse()?),
+            origin: match self.text()? {
+                "alloc" => AllocOrigin::Alloc,
+                "realloc" => AllocOrigin::Realloc,
+                _ => return Err(invalid_data(line, "bad origin")),
+            },
+            realloc_count: self.parse()?,
+            multiplicity: self.parse()?,
+            stack_key: self.parse()?,
+            symbolized: self.parse()?,
+            stack: HeaplessVec::new(),
+            frames: HeaplessVec::new(),
+            last_realloc_stack: None,
+        };
+        self.end()?;
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotFile;
+    use crate::tests::{fake_call, fake_record};
+    use crate::{Call, LeakTracer};
+    use heapless::String as HeaplessString;
+    use std::io::Cursor;
+
+    #[test]
+    fn saved_snapshots_load_into_a_fresh_tracer() {
+        let tracer = LeakTracer::<4>::new();
+        let mut small = fake_record::<4>(0x1000, 16, "small");
+        small.label = Some(HeaplessString::from("tab\there"));
+        let mut moved = fake_record::<4>(0x2000, 4096, "moved");
+        let _ = moved.stack.push(Call {
+            line: None,
+            ..fake_call("back\\slash")
+        });
+        moved.last_realloc_stack = Some(Box::new_in(moved.stack.clone(), std::alloc::System));
+        tracer.load_into(SnapshotFile::new([small, moved]));
+
+        let path = std::env::temp_dir().join(format!("leak-snapshot-{}", std::process::id()));
+        tracer.snapshot_file().save(&path).unwrap();
+        let snapshot = SnapshotFile::<4>::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot.len(), 2);
+
+        let fresh = LeakTracer::<4>::new();
+        fresh.load_into(snapshot);
+        assert_eq!(fresh.leaks_by_size_class(), [(16, 1, 16), (4096, 1, 4096)]);
+        let (saved, loaded) = (tracer.get_leaks(), fresh.get_leaks());
+        assert_eq!(loaded.len(), 2);
+        for (ptr, record) in saved.iter() {
+            let copy = &loaded[ptr];
+            assert_eq!(copy.stack, record.stack);
+            assert_eq!(copy.frames, record.frames);
+            assert_eq!(copy.label, record.label);
+            assert_eq!(copy.seq, record.seq);
+            assert_eq!(copy.stack_key, record.stack_key);
+            assert_eq!(copy.last_realloc_stack, record.last_realloc_stack);
+        }
+        tracer.clear();
+        fresh.clear();
+    }
+
+    #[test]
+    fn other_files_are_invalid_data() {
+        let err = SnapshotFile::<4>::read_from(Cursor::new("not a snapshot\n"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let bad = format!("{}\nframe\t1\n", super::HEADER);
+        let err = SnapshotFile::<4>::read_from(Cursor::new(bad))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("line 2"));
+    }
+}
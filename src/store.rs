@@ -0,0 +1,65 @@
+//! The lock around a tracer's records.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::PoisonError;
+
+/// Which lock guards the records of a tracer, see
+/// [`LeakTracerBuilder::lock_kind`](crate::LeakTracerBuilder::lock_kind).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Busy-waits, the default. Fine without an OS, or with little contention.
+    Spin,
+    /// `std::sync::Mutex`, which puts waiting threads to sleep instead of
+    /// burning CPU when many threads allocate at once.
+    Blocking,
+}
+
+pub(crate) enum RecordLock<T> {
+    Spin(spin::Mutex<T>),
+    Blocking(std::sync::Mutex<T>),
+}
+
+impl<T> RecordLock<T> {
+    pub fn new(kind: LockKind, value: T) -> Self {
+        match kind {
+            LockKind::Spin => Self::Spin(spin::Mutex::new(value)),
+            LockKind::Blocking => Self::Blocking(std::sync::Mutex::new(value)),
+        }
+    }
+
+    pub fn lock(&self) -> RecordGuard<'_, T> {
+        match self {
+            Self::Spin(lock) => RecordGuard::Spin(lock.lock()),
+            // A panic while holding the lock leaves the records consistent
+            // enough to keep reporting.
+            Self::Blocking(lock) => {
+                RecordGuard::Blocking(lock.lock().unwrap_or_else(PoisonError::into_inner))
+            }
+        }
+    }
+}
+
+pub(crate) enum RecordGuard<'a, T> {
+    Spin(spin::MutexGuard<'a, T>),
+    Blocking(std::sync::MutexGuard<'a, T>),
+}
+
+impl<T> Deref for RecordGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Spin(guard) => guard,
+            Self::Blocking(guard) => guard,
+        }
+    }
+}
+
+impl<T> DerefMut for RecordGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Self::Spin(guard) => guard,
+            Self::Blocking(guard) => guard,
+        }
+    }
+}
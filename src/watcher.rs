@@ -0,0 +1,325 @@
+//! Periodic leak growth monitoring, and reports requested from signal
+//! handlers.
+
+use crate::{
+    AgeHook, AllocationRecord, GrowingSite, LeakTracer, ReentrancyGuard, ResolveMode,
+    DEFAULT_NAME_LEN,
+};
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::{HashMap, HashSet};
+use std::alloc::System;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// What the thread started by [`LeakTracer::start_watcher`] found on one tick.
+#[derive(Debug, Clone)]
+pub struct WatchSummary<const STACK_SIZE: usize, const NAME_LEN: usize = DEFAULT_NAME_LEN> {
+    pub live_allocations: usize,
+    pub live_bytes: usize,
+    /// `live_bytes` on the previous tick, 0 on the first one.
+    pub previous_live_bytes: usize,
+    /// Call sites with more live allocations than on the previous tick.
+    pub growing_sites: Vec<GrowingSite<STACK_SIZE, NAME_LEN>>,
+}
+
+/// Keeps the thread started by [`LeakTracer::start_watcher`] or
+/// [`LeakTracer::start_report_thread`] running. Dropping it stops the thread.
+pub struct WatcherHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WatcherHandle {
+    /// Stops the thread and waits for it to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for WatcherHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Starts a thread handing a [`WatchSummary`] to `sink` every `interval`,
+    /// e.g. to log it or send it down a channel.
+    ///
+    /// The watcher compares against its own previous tick, it doesn't change
+    /// what [`LeakTracer::growing_sites`] compares against. Allocations made by
+    /// `sink` are not tracked. Each tick also runs
+    /// [`LeakTracer::check_age_watchdog`].
+    pub fn start_watcher(
+        &'static self,
+        interval: Duration,
+        sink: impl Fn(WatchSummary<STACK_SIZE, NAME_LEN>) + Send + 'static,
+    ) -> WatcherHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            // The thread belongs to the tracer, none of its allocations count.
+            let _guard = ReentrancyGuard::enter();
+            let mut site_counts = HashMap::default();
+            let mut previous_live_bytes = 0;
+            loop {
+                std::thread::park_timeout(interval);
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stats = self.stats();
+                sink(WatchSummary {
+                    live_allocations: stats.live_allocations,
+                    live_bytes: stats.live_bytes,
+                    previous_live_bytes,
+                    growing_sites: self.sites_grown_since(&mut site_counts),
+                });
+                previous_live_bytes = stats.live_bytes;
+                self.check_age_watchdog();
+            }
+        });
+        WatcherHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl<const STACK_SIZE: usize, const NAME_LEN: usize> LeakTracer<STACK_SIZE, NAME_LEN> {
+    /// Calls `hook` once for each live allocation older than `age`, by the
+    /// tracer's clock, as a sign of a definite leak. Checked on each tick of
+    /// [`LeakTracer::start_watcher`], or when calling
+    /// [`LeakTracer::check_age_watchdog`]. Replaces any previous watchdog.
+    pub fn set_age_watchdog(
+        &self,
+        age: Duration,
+        hook: impl Fn(&AllocationRecord<STACK_SIZE, NAME_LEN>) + Send + Sync + 'static,
+    ) {
+        let _guard = ReentrancyGuard::enter();
+        let hook: AgeHook<STACK_SIZE, NAME_LEN> = Arc::new_in(hook, System);
+        *self.inner.age_watchdog.write() = Some((age, hook));
+    }
+
+    pub fn clear_age_watchdog(&self) {
+        self.inner.age_watchdog.write().take();
+    }
+
+    /// Runs the watchdog set with [`LeakTracer::set_age_watchdog`] over the
+    /// live allocations. The hook runs outside of any lock, for the
+    /// allocations it wasn't called for yet, so it may replace or clear the
+    /// watchdog; its own allocations are not tracked.
+    pub fn check_age_watchdog(&self) {
+        let _guard = ReentrancyGuard::enter();
+        let Some((age, hook)) = self.inner.age_watchdog.read().clone() else {
+            return;
+        };
+        let now = self.now();
+        let mut aged = Vec::new();
+        {
+            let allocates = self.inner.allocates.lock();
+            let mut fired = self.inner.aged_seqs.lock();
+            // Forget the freed ones.
+            let live: HashSet<u64, DefaultHashBuilder, System> =
+                allocates.values().map(|record| record.seq).collect();
+            fired.retain(|seq| live.contains(seq));
+            for record in allocates.values() {
+                if now.saturating_sub(record.allocated_at) > age && fired.insert(record.seq) {
+                    aged.push((record.ptr, record.seq));
+                }
+            }
+        }
+        aged.sort_by_key(|&(_, seq)| seq);
+        if self.options.resolve_mode == ResolveMode::Lazy {
+            self.resolve_records(&aged, &mut |_, _| {});
+        }
+        // Those freed meanwhile aren't leaks anymore.
+        let records: Vec<_> = {
+            let allocates = self.inner.allocates.lock();
+            aged.iter()
+                .filter_map(|(ptr, seq)| allocates.get(ptr).filter(|record| record.seq == *seq))
+                .cloned()
+                .collect()
+        };
+        for record in records {
+            hook(&record);
+        }
+    }
+
+    /// Asks the thread started by [`LeakTracer::start_report_thread`] to run
+    /// its report.
+    ///
+    /// This only sets an atomic flag, so unlike every other method it is
+    /// async-signal-safe, and meant to be called from e.g. a `SIGUSR1`
+    /// handler. Installing the handler is up to the caller.
+    pub fn request_report(&self) {
+        self.report_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Starts a thread checking every `poll_interval` whether
+    /// [`LeakTracer::request_report`] was called, and running `report` if so.
+    /// Several requests between two checks run it once.
+    ///
+    /// `report` runs on that thread, where locking and allocating are fine;
+    /// its allocations are not tracked.
+    pub fn start_report_thread(
+        &'static self,
+        poll_interval: Duration,
+        report: impl Fn(&'static Self) + Send + 'static,
+    ) -> WatcherHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let _guard = ReentrancyGuard::enter();
+            while !thread_stop.load(Ordering::SeqCst) {
+                if self.report_requested.swap(false, Ordering::SeqCst) {
+                    report(self);
+                }
+                std::thread::park_timeout(poll_interval);
+            }
+        });
+        WatcherHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{LeakTracer, ResolveMode};
+    use std::alloc::{GlobalAlloc, Layout};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn watcher_reports_growth() {
+        let tracer: &'static LeakTracer<4> = Box::leak(Box::new(LeakTracer::new()));
+        let (sender, receiver) = mpsc::channel();
+        let watcher = tracer.start_watcher(Duration::from_millis(1), move |summary| {
+            let _ = sender.send(summary);
+        });
+
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        let summary = receiver
+            .iter()
+            .find(|summary| summary.live_bytes > summary.previous_live_bytes)
+            .unwrap();
+        assert_eq!(summary.live_bytes, 512);
+        assert_eq!(summary.live_allocations, 1);
+        assert_eq!(summary.growing_sites.len(), 1);
+        // The watcher's baseline is its own.
+        assert_eq!(tracer.growing_sites().len(), 1);
+
+        watcher.stop();
+        unsafe { tracer.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn requested_reports_run_on_the_report_thread() {
+        let tracer: &'static LeakTracer<4> = Box::leak(Box::new(LeakTracer::new()));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+
+        let (sender, receiver) = mpsc::channel();
+        let reporter = tracer.start_report_thread(Duration::from_millis(1), move |tracer| {
+            let _ = sender.send((
+                std::thread::current().id(),
+                tracer.display_leaks().to_string(),
+            ));
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(20)).is_err());
+
+        // What a signal handler would do.
+        tracer.request_report();
+        let (thread, report) = receiver.recv().unwrap();
+        assert_ne!(thread, std::thread::current().id());
+        assert!(report.contains("Allocation (size 64"));
+
+        reporter.stop();
+        unsafe { tracer.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn age_watchdog_fires_once_per_aged_allocation() {
+        static NOW_SECS: AtomicU64 = AtomicU64::new(0);
+        fn fake_clock() -> Duration {
+            Duration::from_secs(NOW_SECS.load(Ordering::SeqCst))
+        }
+
+        let tracer = LeakTracer::<4>::new();
+        tracer.set_clock(fake_clock);
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let hook_fired = fired.clone();
+        tracer.set_age_watchdog(Duration::from_secs(5), move |record| {
+            hook_fired.lock().unwrap().push(record.ptr);
+        });
+
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let old = unsafe { tracer.alloc(layout) };
+        NOW_SECS.store(4, Ordering::SeqCst);
+        let young = unsafe { tracer.alloc(layout) };
+        tracer.check_age_watchdog();
+        assert!(fired.lock().unwrap().is_empty());
+
+        NOW_SECS.store(8, Ordering::SeqCst);
+        tracer.check_age_watchdog();
+        tracer.check_age_watchdog();
+        assert_eq!(*fired.lock().unwrap(), [old as usize]);
+
+        NOW_SECS.store(10, Ordering::SeqCst);
+        tracer.check_age_watchdog();
+        assert_eq!(*fired.lock().unwrap(), [old as usize, young as usize]);
+
+        unsafe {
+            tracer.dealloc(old, layout);
+            tracer.dealloc(young, layout);
+        }
+    }
+
+    #[test]
+    fn age_watchdog_hooks_may_replace_the_watchdog() {
+        let tracer: &'static LeakTracer<8> = Box::leak(Box::new(
+            LeakTracer::builder()
+                .resolve_mode(ResolveMode::Lazy)
+                .names_only(true)
+                .build(),
+        ));
+        tracer.set_clock(|| Duration::from_secs(0));
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        tracer.set_clock(|| Duration::from_secs(10));
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        tracer.set_age_watchdog(Duration::from_secs(5), move |record| {
+            hook_seen.lock().unwrap().push(record.clone());
+            tracer.set_age_watchdog(Duration::from_secs(60), |_| {});
+        });
+        tracer.check_age_watchdog();
+
+        // Resolved like any other lazy record, without source locations.
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(seen[0].symbolized);
+        assert!(seen[0].stack.iter().all(|call| call.filename.is_none()));
+        assert!(tracer.get_leaks()[&(ptr as usize)].symbolized);
+        assert_eq!(
+            tracer.inner.age_watchdog.read().as_ref().unwrap().0,
+            Duration::from_secs(60)
+        );
+
+        unsafe { tracer.dealloc(ptr, layout) };
+    }
+}
@@ -0,0 +1,40 @@
+use leak_detect_allocator::{DoubleFree, LeakTracer};
+use std::alloc::{GlobalAlloc, Layout};
+use std::process::Command;
+
+const CHILD: &str = "LEAK_DETECT_DOUBLE_FREE_CHILD";
+
+#[test]
+fn strict_mode_aborts_on_double_free() {
+    if std::env::var_os(CHILD).is_some() {
+        let tracer = LeakTracer::<4>::builder()
+            .on_double_free(DoubleFree::Abort)
+            .build();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { tracer.alloc(layout) };
+        unsafe {
+            tracer.dealloc(ptr, layout);
+            tracer.dealloc(ptr, layout);
+        }
+        // Only reached if the double free went unnoticed.
+        std::process::exit(0);
+    }
+
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "strict_mode_aborts_on_double_free",
+            "--nocapture",
+        ])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(output.status.signal(), Some(6));
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("double free of"), "{}", stderr);
+}
@@ -0,0 +1,17 @@
+use leak_detect_allocator::LeakTracer;
+
+leak_detect_allocator::leak_tracer!(LeakTracer<8>);
+
+#[test]
+fn get_leaks_records_nothing_itself() {
+    let tracer = leak_tracer();
+    let kept = vec![0u8; 4321];
+
+    // Nothing is allocated in between, and `before` stays alive: anything
+    // the first call left tracked, its result included, shows up in `after`.
+    let before = tracer.get_leaks();
+    let after = tracer.get_leaks();
+    assert_eq!(after.len(), before.len());
+    assert!(before.contains_key(&(kept.as_ptr() as usize)));
+    assert!(after.keys().all(|ptr| before.contains_key(ptr)));
+}
@@ -0,0 +1,25 @@
+use leak_detect_allocator::LeakTracer;
+
+leak_detect_allocator::leak_tracer!(LeakTracer<8>, report_at_exit);
+
+#[test]
+fn macro_installs_a_working_global_tracer() {
+    let tracer = leak_tracer();
+    let leak = Box::new([7u8; 1234]);
+    let ptr = &*leak as *const _ as usize;
+
+    let record = tracer
+        .get_leaks()
+        .remove(&ptr)
+        .expect("allocation is tracked");
+    assert_eq!(record.size, 1234);
+
+    drop(leak);
+    assert!(!tracer.get_leaks().contains_key(&ptr));
+}
+
+#[test]
+fn macro_tracer_is_the_global_one() {
+    let tracer = leak_tracer();
+    assert!(std::ptr::eq(LeakTracer::<8>::global().unwrap(), tracer));
+}